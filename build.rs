@@ -1,6 +1,7 @@
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     tonic_build::configure()
         .protoc_arg("--experimental_allow_proto3_optional")
+        .btree_map([".block.INodeBlock.xattrs"]) // blocks are hashed by their encoding, which has to be deterministic
         .compile(&[
             "proto/block.proto",
             "proto/middleware.proto",
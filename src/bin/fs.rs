@@ -1,16 +1,20 @@
 use std::fs;
+use std::path::Path;
+use std::process::exit;
+use std::sync::mpsc::channel;
+use std::time::Duration;
 use clap::{Arg, Command};
 use config::{Config, ConfigError, File};
-use fuser::MountOption;
+use fuser::{MountOption, Session};
 use rsa::pkcs1v15;
 use rsa::pkcs8::{DecodePrivateKey, DecodePublicKey};
 use rsa::sha2::Sha256;
 use serde::{Deserialize, Serialize};
 use tonic::transport::{Certificate, ClientTlsConfig};
 
-use lib::cache::Cache;
+use lib::cache::{Cache, Cutoff};
 use lib::client::{BlockClient, FSMiddlewareClient, INodeClient};
-use lib::fs::CFS;
+use lib::fs::{CFS, notify_kernel, SharedCFS, WriteBack};
 
 fn main() {
     env_logger::init();
@@ -26,6 +30,16 @@ fn main() {
                 .required(true)
                 .index(2)
                 .help("Act as a client, and mount FUSE at given path")
+        ).arg(
+            Arg::new("AT_BLOCK")
+                .long("at-block")
+                .conflicts_with("AT_TIME")
+                .help("Mount the file system read-only as of the inode block with this hash")
+        ).arg(
+            Arg::new("AT_TIME")
+                .long("at-time")
+                .value_parser(clap::value_parser!(i64))
+                .help("Mount the file system read-only as of this Unix timestamp in seconds")
         ).get_matches();
 
     let mut options = vec![
@@ -35,37 +49,67 @@ fn main() {
 
     let config = ClientConfig::new(matches.get_one::<String>("CONFIG_FILE").unwrap()).unwrap();
     let mountpoint = matches.get_one::<String>("MOUNT_POINT").unwrap();
+    if config.allow_other {
+        options.push(MountOption::AllowOther); // requires user_allow_other in /etc/fuse.conf unless run as root
+    }
 
     let ca = Certificate::from_pem(fs::read(config.tls.ca).unwrap());
     let tls_config = ClientTlsConfig::new().ca_certificate(ca);
 
+    let cutoff = if let Some(hash) = matches.get_one::<String>("AT_BLOCK") {
+        Some(Cutoff::Block(hash.clone()))
+    } else {
+        matches.get_one::<i64>("AT_TIME").map(|x| Cutoff::Timestamp(x * 1_000_000_000))
+    };
+
     let middleware_client: Option<FSMiddlewareClient>;
-    if let Some(middleware_config) = config.middleware {
+    if let (Some(middleware_config), None) = (config.middleware, &cutoff) {
         middleware_client = Some(FSMiddlewareClient::connect(
             &middleware_config.url, tls_config.clone(), fs::read_to_string(middleware_config.verifying_key).unwrap(),
             pkcs1v15::SigningKey::<Sha256>::read_pkcs8_pem_file(middleware_config.signing_key).unwrap(), config.is_crypto_enabled));
         options.push(MountOption::RW);
     } else {
-        middleware_client = None;
+        middleware_client = None; // snapshots are never written to
         options.push(MountOption::RO);
     }
 
-    fuser::mount2(CFS {
-        cache: Cache::new(
-            INodeClient::connect(config.inode_server.url.as_ref(), tls_config.clone(), config.inode_server.cache_size,
-                                 pkcs1v15::VerifyingKey::<Sha256>::read_public_key_pem_file(config.inode_server.verifying_key).unwrap(), config.is_crypto_enabled),
-            BlockClient::connect(config.data_server.url.as_ref(), tls_config.clone(), config.data_server.cache_size,
-                                 pkcs1v15::VerifyingKey::<Sha256>::read_public_key_pem_file(config.data_server.verifying_key).unwrap(), config.is_crypto_enabled),
-            middleware_client, config.inode_server.root, config.data_server.root, config.block_size
-        ),
-    }, mountpoint, &options).unwrap();
+    let mut write_back = WriteBack::default();
+    if let Some(config) = &config.write_back {
+        write_back.max_bytes = config.max_dirty_bytes;
+        write_back.max_age = Duration::from_secs(config.max_dirty_seconds);
+    }
+
+    let cache = Cache::new(
+        INodeClient::connect(&config.inode_server.urls, tls_config.clone(), config.inode_server.cache_size,
+                             pkcs1v15::VerifyingKey::<Sha256>::read_public_key_pem_file(config.inode_server.verifying_key).unwrap(), config.is_crypto_enabled),
+        BlockClient::connect(&config.data_server.urls, tls_config.clone(), config.data_server.cache_size,
+                             pkcs1v15::VerifyingKey::<Sha256>::read_public_key_pem_file(config.data_server.verifying_key).unwrap(), config.is_crypto_enabled),
+        middleware_client, config.inode_server.root, config.data_server.root, config.block_size, cutoff,
+        config.refresh_interval.map(Duration::from_secs)
+    );
+    let cache = match cache {
+        Ok(cache) => cache,
+        Err(e) => {
+            eprintln!("Unable to load the file system: {}", e);
+            exit(1);
+        }
+    };
+
+    let (notifications, changes) = channel();
+    let mut session = Session::new(SharedCFS::new(CFS::new(
+        cache,
+        write_back,
+        Some(notifications),
+    )), Path::new(mountpoint), &options).unwrap();
+    notify_kernel(session.notifier(), changes);
+    session.run().unwrap();
 }
 
 
 #[derive(Serialize, Deserialize)]
 #[allow(unused)]
 #[serde(rename_all = "camelCase")]
-struct TLS {
+struct Tls {
     pub ca: String,
 }
 
@@ -82,26 +126,39 @@ struct Middleware {
 #[allow(unused)]
 #[serde(rename_all = "camelCase")]
 struct Server {
-    pub url: String,
+    pub urls: Vec<String>, // replicas of the capsule, tried in order
+
     pub cache_size: usize,
     pub root: String,
     pub verifying_key: String
 }
 
+#[derive(Serialize, Deserialize)]
+#[allow(unused)]
+#[serde(rename_all = "camelCase")]
+struct WriteBackConfig {
+    pub max_dirty_bytes: usize,
+    pub max_dirty_seconds: u64,
+}
+
 #[derive(Serialize, Deserialize)]
 #[allow(unused)]
 #[serde(rename_all = "camelCase")]
 struct ClientConfig {
     pub block_size: u16,
     pub is_crypto_enabled: bool,
+    #[serde(default)]
+    pub allow_other: bool,
     pub data_server: Server,
     pub inode_server: Server,
     pub middleware: Option<Middleware>,
-    pub tls: TLS,
+    pub write_back: Option<WriteBackConfig>,
+    pub refresh_interval: Option<u64>, // seconds between polls for new leafs, none by default
+    pub tls: Tls,
 }
 
 impl ClientConfig {
-    pub fn new(config_file: &String) -> Result<Self, ConfigError> {
+    pub fn new(config_file: &str) -> Result<Self, ConfigError> {
         let s = Config::builder()
             .add_source(File::with_name(config_file))
             .build()?;
@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fs;
 
 use clap::{Arg, Command};
@@ -95,6 +95,15 @@ fn main() {
             filename: "".into(),
             size: 0,
             kind: Kind::Directory.into(),
+            moved_from: "".into(),
+            target: vec![],
+            mode: None,
+            uid: None,
+            gid: None,
+            mtime: None,
+            link_id: "".into(),
+            xattrs: BTreeMap::new(),
+            base: "".into(),
             hashes: vec![],
             write_allow_list: vec![id.clone()],
         })),
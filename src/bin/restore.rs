@@ -0,0 +1,38 @@
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::process::exit;
+
+use clap::{Arg, Command};
+
+use lib::fs::XATTR_RESTORE;
+
+/* This program brings back a deleted file or directory of a mounted file system. It asks the
+ * client to publish the last version before the deletion by setting an xattr on the parent.
+ */
+fn main() {
+    let matches = Command::new("restore")
+        .arg(
+            Arg::new("PATH")
+                .required(true)
+                .index(1)
+                .help("Path of the deleted entry in the mounted file system")
+        ).get_matches();
+
+    let path = Path::new(matches.get_one::<String>("PATH").unwrap());
+    let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+        eprintln!("{} does not name an entry", path.display());
+        exit(1);
+    };
+    let parent = if parent.as_os_str().is_empty() { Path::new(".") } else { parent };
+
+    let parent_path = CString::new(parent.as_os_str().as_bytes()).unwrap();
+    let xattr = CString::new(XATTR_RESTORE).unwrap();
+    let result = unsafe {
+        libc::setxattr(parent_path.as_ptr(), xattr.as_ptr(), name.as_bytes().as_ptr() as *const libc::c_void, name.len(), 0)
+    };
+    if result != 0 {
+        eprintln!("Unable to restore {}: {}", path.display(), std::io::Error::last_os_error());
+        exit(1);
+    }
+}
@@ -1,24 +1,25 @@
 use std::fs;
 use std::sync::Arc;
+use std::time::Duration;
 
 use clap::{Arg, Command};
 use config::{Config, ConfigError, File};
 use futures::future::join_all;
-use prost::Message;
 use rsa::pkcs1v15;
 use rsa::pkcs8::DecodePublicKey;
 use rsa::sha2::Sha256;
 use serde::Deserialize;
-use tokio::sync::Mutex;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{broadcast, watch, Mutex};
 use tonic::{
     transport::{
-        Identity, Server, ServerTlsConfig,
+        Certificate, ClientTlsConfig, Identity, Server, ServerTlsConfig,
     },
 };
 
 use lib::proto::data_capsule::data_capsule_server::DataCapsuleServer;
-use lib::proto::data_capsule::DataCapsuleServerData;
-use lib::server::MyDataCapsule;
+use lib::replication::{anti_entropy, Replicator};
+use lib::server::{DirectoryStorage, MemoryStorage, MyDataCapsule, Quota, Storage};
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -32,6 +33,8 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     let config = ServersConfig::new(matches.get_one::<String>("CONFIG_FILE").unwrap()).unwrap();
     let mut v = Vec::new();
+    let mut storages = Vec::new();
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
 
     for server in config.servers {
         let identity = Identity::from_pem(
@@ -39,29 +42,102 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             fs::read(server.tls.private_key).unwrap(),
         );
         let data_capsule_addr = format!("{}:{}", server.address, server.port).parse()?;
-        let data_capsule = MyDataCapsule {
-            data: Arc::new(Mutex::new(DataCapsuleServerData::decode(fs::read(server.data_file).unwrap().as_slice()).unwrap())),
-            verifying_key: pkcs1v15::VerifyingKey::<Sha256>::read_public_key_pem_file(server.verifying_key).unwrap(),
-            enable_crypto: server.is_crypto_enabled
+        let storage: Box<dyn Storage> = match server.storage {
+            StorageKind::Memory => Box::new(MemoryStorage::open(&server.data_file, server.log_file.as_deref()).unwrap()),
+            StorageKind::Directory => Box::new(DirectoryStorage::open(server.data_dir.as_ref().unwrap(), Some(&server.data_file)).unwrap()),
         };
+        let storage = Arc::new(Mutex::new(storage));
+        if let Some(interval) = server.snapshot_interval {
+            tokio::spawn(snapshot_periodically(storage.clone(), Duration::from_secs(interval)));
+        }
+        storages.push(storage.clone());
+
+        let replicator = server.peers.map(|peers| {
+            let ca = Certificate::from_pem(fs::read(server.tls.ca.as_ref().unwrap()).unwrap());
+            Replicator::new(peers, ClientTlsConfig::new().ca_certificate(ca))
+        });
+
+        let mut shutdown = shutdown_rx.clone();
+        let data_capsule = Arc::new(MyDataCapsule {
+            storage,
+            updates: broadcast::channel(1024).0,
+            replicator,
+            quota: Quota {
+                max_blocks: server.quota.max_blocks,
+                max_bytes: server.quota.max_bytes,
+            },
+            verifying_key: pkcs1v15::VerifyingKey::<Sha256>::read_public_key_pem_file(server.verifying_key).unwrap(),
+            enable_crypto: server.is_crypto_enabled,
+            admin_token: server.admin_token,
+            shutdown: shutdown_rx.clone(),
+        });
+        if data_capsule.replicator.is_some() {
+            let interval = server.anti_entropy_interval.unwrap_or(10);
+            tokio::spawn(anti_entropy(data_capsule.clone(), Duration::from_secs(interval)));
+        }
         v.push(Server::builder()
             .tls_config(ServerTlsConfig::new().identity(identity.clone()))?
-            .add_service(DataCapsuleServer::new(data_capsule))
-            .serve(data_capsule_addr)
+            .add_service(DataCapsuleServer::from_arc(data_capsule))
+            .serve_with_shutdown(data_capsule_addr, async move {
+                shutdown.changed().await.ok();
+            })
         );
         println!("Listening {}:{}", server.address, server.port);
     }
 
+    tokio::spawn(async move {
+        let mut sigterm = signal(SignalKind::terminate()).unwrap();
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {},
+            _ = sigterm.recv() => {},
+        }
+        println!("Shutting down");
+        shutdown_tx.send(true).unwrap();
+    });
+
     join_all(v).await; // TODO: panic() messages will not show up
+
+    for storage in storages {
+        if let Err(e) = storage.lock().await.snapshot() {
+            eprintln!("Unable to write snapshot: {}", e);
+        }
+    }
     Ok(())
 }
 
+async fn snapshot_periodically(storage: Arc<Mutex<Box<dyn Storage>>>, period: Duration) {
+    let mut interval = tokio::time::interval(period);
+    interval.tick().await; // the first tick completes immediately
+    loop {
+        interval.tick().await;
+        if let Err(e) = storage.lock().await.snapshot() {
+            eprintln!("Unable to write snapshot: {}", e);
+        }
+    }
+}
+
 #[derive(Debug, Deserialize)]
 #[allow(unused)]
 #[serde(rename_all = "camelCase")]
-struct TLS {
+struct Tls {
     certificate: String,
-    private_key: String
+    private_key: String,
+    ca: Option<String> // to verify peers
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct QuotaConfig {
+    max_blocks: Option<u64>,
+    max_bytes: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+enum StorageKind {
+    #[default]
+    Memory,
+    Directory
 }
 
 #[derive(Debug, Deserialize)]
@@ -71,8 +147,18 @@ struct ServerConfig {
     is_crypto_enabled: bool,
     address: String,
     port: u16,
+    #[serde(default)]
+    storage: StorageKind,
     data_file: String,
-    tls: TLS,
+    log_file: Option<String>,
+    data_dir: Option<String>,
+    snapshot_interval: Option<u64>, // seconds
+    peers: Option<Vec<String>>, // urls of the other servers hosting this capsule
+    anti_entropy_interval: Option<u64>, // seconds
+    #[serde(default)]
+    quota: QuotaConfig,
+    admin_token: Option<String>, // for the Snapshot RPC
+    tls: Tls,
     verifying_key: String
 }
 
@@ -84,7 +170,7 @@ struct ServersConfig {
 }
 
 impl ServersConfig {
-    pub fn new(config_file: &String) -> Result<Self, ConfigError> {
+    pub fn new(config_file: &str) -> Result<Self, ConfigError> {
         let s = Config::builder()
             .add_source(File::with_name(config_file))
             .build()?;
@@ -1,15 +1,37 @@
-use std::collections::HashMap;
+use std::cmp::min;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::error::Error;
 use std::ffi::OsStr;
 use std::os::unix::ffi::OsStrExt;
 use std::sync::Arc;
+use std::sync::mpsc::{channel, Receiver};
+use std::time::Duration;
 
 use futures::executor::block_on;
+use log::warn;
 
 use crate::client::{BlockClient, FSMiddlewareClient, INodeClient};
+use crate::crypto::is_allowed_writer;
 use crate::inode::INode;
 use crate::proto::block::{DataCapsuleBlock, DataCapsuleFileSystemBlock, INodeBlock};
 use crate::proto::block::data_capsule_file_system_block::Block;
 use crate::proto::block::i_node_block::Kind;
+use crate::proto::data_capsule::StatsResponse;
+
+// an entry that may be cached by the kernel and was changed by resolving a block
+pub struct Change {
+    pub parent: u64,
+    pub name: Vec<u8>,
+    pub ino: u64,
+}
+
+/* The point in history a snapshot mount shows. For a block, that is what its writer had seen: the
+ * versions it was derived from, and for entries it does not derive from, those published before it.
+ */
+pub enum Cutoff {
+    Block(String),
+    Timestamp(i64), // nanoseconds since the epoch
+}
 
 pub struct Cache {
     inode_client: INodeClient,
@@ -17,43 +39,111 @@ pub struct Cache {
     middleware_client: Option<Arc<FSMiddlewareClient>>,
     inodes: Vec<(INode, Vec<INode>)>, // Vec<Node, Children>
     hash_to_ino: HashMap<String, u64>, // Hash -> INode.ino
+    moved_away: HashMap<(u64, Vec<u8>), (i64, u64)>, // (parent ino, filename) -> (timestamp, ino) of the entry renamed away from there
+    link_ino: HashMap<String, u64>, // INodeBlock.link_id -> INode.ino
+    link_parents: HashMap<u64, HashSet<u64>>, // INode.ino of a hard linked file -> INode.ino of every directory that had one of its entries
+    history: HashMap<u64, Vec<(INode, u64)>>, // INode.ino -> every version resolved so far and the uid of its author
+    versions: HashMap<String, (String, i64, u64)>, // Hash -> (INodeBlock.base, timestamp, uid of the author)
+    conflicts: HashMap<u64, (u64, String)>, // INode.ino of a conflict entry -> (INode.ino of the file, hash of the version that lost)
+    updates: Receiver<(String, Option<DataCapsuleBlock>)>, // blocks pushed by the inode server
     data_root: String,
-    block_size: usize
+    block_size: usize,
+    cutoff: Option<i64>, // blocks with a later timestamp are ignored
+    ancestry: HashSet<String>, // of the cutoff block, see Cutoff
+    cutoff_entries: HashSet<(u64, Vec<u8>)>, // (parent ino, filename) of the entries with a version in ancestry
+    changes: Vec<Change>, // since the last refresh
 }
 
 impl Cache {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         client: INodeClient,
         block_client: BlockClient,
         middleware: Option<FSMiddlewareClient>,
         inode_root: String,
         data_root: String,
-        block_size: u16) -> Cache {
+        block_size: u16,
+        cutoff: Option<Cutoff>,
+        refresh_interval: Option<Duration>) -> Result<Cache, Box<dyn Error>> {
+        let (sender, updates) = channel();
+        let mut ancestry = HashSet::new();
+        let cutoff = match cutoff {
+            Some(Cutoff::Block(hash)) => {
+                let block = block_on(client.get(hash.clone())).map_err(|e| format!("Unknown block {}: {}", hash, e))?;
+                ancestry = Cache::ancestry(&client, &hash)?;
+                Some(block.timestamp)
+            }
+            Some(Cutoff::Timestamp(timestamp)) => Some(timestamp),
+            None => None,
+        };
+        // a snapshot never changes
+        let poll = match (&cutoff, refresh_interval) {
+            (None, Some(interval)) => Some((sender.clone(), interval)),
+            _ => None,
+        };
+        if cutoff.is_none() {
+            client.subscribe(sender); // before building, so nothing published meanwhile is missed
+        }
+
         let mut cache = Cache {
             inode_client: client,
             block_client: Arc::new(block_client),
             middleware_client: None,
             inodes: Vec::new(),
             hash_to_ino: HashMap::new(),
+            moved_away: HashMap::new(),
+            link_ino: HashMap::new(),
+            link_parents: HashMap::new(),
+            history: HashMap::new(),
+            versions: HashMap::new(),
+            conflicts: HashMap::new(),
+            updates,
             data_root,
-            block_size: block_size as usize
+            block_size: block_size as usize,
+            cutoff,
+            ancestry,
+            cutoff_entries: HashSet::new(),
+            changes: Vec::new(),
         };
         if let Some(middleware) = middleware {
             cache.middleware_client = Some(Arc::new(middleware));
         }
-        cache.build(inode_root);
-        return cache;
+        let leafs = cache.build(inode_root)?;
+        if let Some((sender, interval)) = poll {
+            cache.inode_client.poll_leafs(sender, interval, leafs); // in case pushed blocks are missed
+        }
+        return Ok(cache);
     }
 
-    fn build(&mut self, root: String) {
-        let block = block_on(self.inode_client.get(root.clone())).unwrap();
-        if let Block::Inode(data) = block.fs.unwrap().block.unwrap() {
+    // the blocks hash was derived from: its parents, earlier versions and the versions it was moved from
+    fn ancestry(client: &INodeClient, hash: &str) -> Result<HashSet<String>, Box<dyn Error>> {
+        let mut ancestry = HashSet::new();
+        let mut pending = vec![hash.to_string()];
+        while let Some(hash) = pending.pop() {
+            if hash.is_empty() || !ancestry.insert(hash.clone()) {
+                continue;
+            }
+            let block = block_on(client.get(hash.clone())).map_err(|e| format!("Unable to get block {}: {}", hash, e))?;
+            pending.push(block.prev_hash);
+            if let Some(Block::Inode(data)) = block.fs.and_then(|x| x.block) {
+                pending.push(data.base);
+                pending.push(data.moved_from);
+            }
+        }
+        Ok(ancestry)
+    }
+
+    // returns the leafs the tree was built from
+    fn build(&mut self, root: String) -> Result<Vec<String>, Box<dyn Error>> {
+        let block = block_on(self.inode_client.get(root.clone())).map_err(|e| format!("Unable to get root {}: {}", root, e))?;
+        if let Some(Block::Inode(data)) = block.fs.and_then(|x| x.block) {
             let inode = INode{
                 hash: root.clone(),
                 ino: 1,
                 parent_hash: root.clone(),
                 block: data,
                 timestamp: block.timestamp,
+                created: block.timestamp,
                 block_client: self.block_client.clone(),
                 middleware_client: self.middleware_client.clone(),
                 journal: HashMap::new(),
@@ -65,15 +155,36 @@ impl Cache {
             self.inodes.push((inode, Vec::new()));
             self.hash_to_ino.insert(root, 1);
 
-            let leafs = self.inode_client.get_leafs().unwrap();
-            for leaf in leafs {
-                self.resolve(leaf);
-            }
+            // the versions the cutoff block was derived from win over concurrent ones
+            let ancestry: Vec<String> = self.ancestry.iter().cloned().collect();
+            self.resolve_all(ancestry);
+            let leafs = self.inode_client.get_leafs()?;
+            self.resolve_all(leafs.clone());
+            Ok(leafs)
         } else {
-            panic!();
+            Err(format!("Root {} is not an inode", root).into())
         }
     }
 
+    // apply the blocks other clients have published since the last call, returns the entries they changed
+    pub fn refresh(&mut self) -> Vec<Change> {
+        self.changes.clear(); // made by this client, the kernel already knows
+        let mut hashes = vec![];
+        while let Ok((hash, block)) = self.updates.try_recv() {
+            if self.hash_to_ino.contains_key(&hash) {
+                continue;
+            }
+            match block {
+                Some(block) => {
+                    self.resolve_block(hash, block);
+                }
+                None => hashes.push(hash),
+            }
+        }
+        self.resolve_all(hashes);
+        return std::mem::take(&mut self.changes);
+    }
+
     pub fn find_child_node(&self, ino: u64, name: &OsStr) -> Option<INode> {
         if ino > self.num_inodes() {
             return None;
@@ -83,50 +194,267 @@ impl Cache {
         return nodes.iter().find(|x| OsStr::from_bytes(&x.block.filename) == name && !x.is_deleted()).cloned();
     }
 
-    pub fn delete(&mut self, uid: u32, ino: u64) {
-        let mut block = self.get_inode(ino).0.block;
+    pub fn delete(&mut self, uid: u32, entry: &INode) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let mut block = entry.block.clone();
         if block.kind == Kind::Directory.into() {
             block.kind = Kind::DeletedFolder.into();
         } else if block.kind == Kind::RegularFile.into() {
             block.kind = Kind::DeletedRegularFile.into();
+        } else if block.kind == Kind::Symlink.into() {
+            block.kind = Kind::DeletedSymlink.into();
         }
         block.size = 0;
         block.hashes = vec![];
+        block.moved_from = String::new();
+        block.base = entry.hash.clone();
 
-        self.update(uid, ino, block);
+        // other hard links of the file are not affected
+        self.put_block(uid, entry.parent_hash.clone(), block)
     }
 
-    pub fn update(&mut self, uid: u32, ino: u64, block: INodeBlock) {
-        let block = DataCapsuleFileSystemBlock {
-            prev_hash:self.get_inode(ino).0.parent_hash,
-            block: Some(Block::Inode(block)),
-            updated_by: Some(self.middleware_client.clone().unwrap().get_id(uid as u64)),
-            signature: vec![],
+    /* Publishes the last version of the deleted entry name of parent_ino that was not deleted, which
+     * brings the entry back with its old ino. A directory comes back with the entries that were deleted
+     * along with it. Returns false if there is no such version.
+     */
+    pub fn restore(&mut self, uid: u32, parent_ino: u64, name: &OsStr) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        let Some(entry) = self.get_inode(parent_ino).1.into_iter().find(|x| OsStr::from_bytes(&x.block.filename) == name) else {
+            return Ok(false);
         };
-        let response = block_on(self.middleware_client.clone().unwrap().put_inode(block)).unwrap();
-        self.resolve_block(response.hash.unwrap(), response.block.unwrap());
+        // planned up front, as a directory that is brought back starts without entries
+        let mut plan = vec![];
+        if !self.plan_restore(parent_ino, &entry, &mut plan) {
+            return Ok(false);
+        }
+        for (parent_ino, version, tombstone) in plan {
+            let mut block = version.block;
+            block.moved_from = String::new();
+            block.base = tombstone;
+            self.put_block(uid, self.get_inode(parent_ino).0.hash, block)?;
+        }
+        Ok(true)
+    }
+
+    /* Adds (parent ino, version to publish, hash of the tombstone) for the deleted entry of parent_ino
+     * to plan, parents before their entries. The entries of a directory that were deleted since any
+     * other change to it, e.g. by rm -r, are restored with it.
+     */
+    fn plan_restore(&self, parent_ino: u64, entry: &INode, plan: &mut Vec<(u64, INode, String)>) -> bool {
+        let Some(version) = self.last_live_version(parent_ino, entry) else {
+            return false;
+        };
+        plan.push((parent_ino, version, entry.hash.clone()));
+        if entry.block.kind == Kind::DeletedFolder.into() {
+            let children = &self.inodes[entry.ino as usize].1;
+            let last_change = children.iter()
+                .filter_map(|x| if x.is_deleted() { self.last_live_version(entry.ino, x).map(|x| x.timestamp) } else { Some(x.timestamp) })
+                .max();
+            for child in children.iter().filter(|x| x.is_deleted() && last_change.is_some_and(|last| x.timestamp > last)) {
+                self.plan_restore(entry.ino, child, plan);
+            }
+        }
+        true
+    }
+
+    fn last_live_version(&self, parent_ino: u64, entry: &INode) -> Option<INode> {
+        return self.history.get(&entry.ino).into_iter().flatten()
+            .map(|(version, _)| version)
+            .filter(|x| !x.is_deleted() && x.block.filename == entry.block.filename && self.get_ino(x.parent_hash.clone()) == parent_ino)
+            .max_by_key(|x| x.timestamp)
+            .cloned();
+    }
+
+    pub fn update(&mut self, uid: u32, ino: u64, block: INodeBlock) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.try_update(uid, ino, None, block)
+    }
+
+    /* Publishes a new version of the file, returns once the middleware has accepted it. base is the
+     * version the change was made to, if it may be older than the current one. A hard linked file is
+     * only updated through one of its entries, as all of them resolve to the latest version.
+     */
+    pub fn try_update(&mut self, uid: u32, ino: u64, base: Option<&INode>, mut block: INodeBlock) -> Result<(), Box<dyn Error + Send + Sync>> {
+        if ino == 1 {
+            return Err("The root is not an entry of a directory, it has no later versions".into());
+        }
+        block.moved_from = String::new(); // only the rename itself refers to the old location
+        let (entry, base) = self.update_entry(ino, base, !block.link_id.is_empty());
+        block.base = base;
+        block.filename = entry.block.filename;
+        self.put_block(uid, entry.parent_hash, block)
     }
 
-    pub fn create(&mut self, uid: u32, parent_ino: u64, name: &OsStr, kind: Kind) {
+    // the entry a new version of ino is published through, and the version it is based on
+    fn update_entry(&self, ino: u64, base: Option<&INode>, linked: bool) -> (INode, String) {
+        let current = self.get_inode(ino).0;
+        let same_entry = |x: &INode, y: &INode| x.block.filename == y.block.filename && self.get_ino(x.parent_hash.clone()) == self.get_ino(y.parent_hash.clone());
+        let mut entry = current.clone();
+        if linked {
+            let entries = self.live_entries(ino);
+            let preferred = base.and_then(|base| entries.iter().find(|x| same_entry(x, base)))
+                .or_else(|| entries.iter().find(|x| same_entry(x, &current)))
+                .or(entries.first());
+            if let Some(preferred) = preferred {
+                entry = preferred.clone();
+            }
+        }
+        let base = match base {
+            Some(base) if same_entry(base, &entry) => base.hash.clone(),
+            _ => entry.hash.clone(),
+        };
+        return (entry, base);
+    }
+
+    pub fn create(&mut self, uid: u32, gid: u32, parent_ino: u64, name: &OsStr, kind: Kind, mode: u32) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let mut inode_block = self.new_child(parent_ino, name, kind);
+        inode_block.mode = Some(mode & 0o7777);
+        inode_block.uid = Some(uid);
+        inode_block.gid = Some(gid);
+        self.put_block(uid, self.get_inode(parent_ino).0.hash, inode_block)
+    }
+
+    pub fn symlink(&mut self, uid: u32, gid: u32, parent_ino: u64, name: &OsStr, target: &OsStr) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let mut inode_block = self.new_child(parent_ino, name, Kind::Symlink);
+        inode_block.mode = Some(0o777);
+        inode_block.uid = Some(uid);
+        inode_block.gid = Some(gid);
+        inode_block.target = Vec::from(target.as_bytes());
+        inode_block.size = inode_block.target.len() as u64;
+        self.put_block(uid, self.get_inode(parent_ino).0.hash, inode_block)
+    }
+
+    /* Adds another entry for the file. All entries of a file carry the same link id, the hash of
+     * the entry that was linked first, and resolve to the same ino.
+     */
+    pub fn link(&mut self, uid: u32, ino: u64, new_parent_ino: u64, new_name: &OsStr) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let node = self.materialize(uid, self.get_inode(ino).0)?;
+        let mut inode_block = node.block.clone();
+        if inode_block.link_id.is_empty() {
+            inode_block.link_id = node.hash.clone();
+            self.update(uid, ino, inode_block.clone())?;
+        }
+        inode_block.filename = Vec::from(new_name.as_bytes());
+        inode_block.moved_from = String::new();
+        inode_block.base = String::new();
+        self.put_block(uid, self.get_inode(new_parent_ino).0.hash, inode_block)
+    }
+
+    /* A conflict entry only exists locally, it is published under its own name before it can be
+     * referred to by other blocks.
+     */
+    fn materialize(&mut self, uid: u32, entry: INode) -> Result<INode, Box<dyn Error + Send + Sync>> {
+        if self.conflicts.get(&entry.ino).map(|(_, hash)| hash) != Some(&entry.hash) {
+            return Ok(entry);
+        }
+        let parent_ino = self.get_ino(entry.parent_hash.clone());
+        let mut block = entry.block.clone();
+        block.base = entry.hash.clone();
+        self.put_block(uid, self.get_inode(parent_ino).0.hash, block)?;
+        return Ok(self.find_child_node(parent_ino, OsStr::from_bytes(&entry.block.filename)).unwrap_or(entry));
+    }
+
+    fn new_child(&self, parent_ino: u64, name: &OsStr, kind: Kind) -> INodeBlock {
         let parent_block = self.get_inode(parent_ino).0;
 
-        let inode_block = INodeBlock {
-            filename: Vec::from(name.to_str().unwrap()),
+        INodeBlock {
+            filename: Vec::from(name.as_bytes()),
             size: 0,
             kind: kind.into(),
+            moved_from: String::new(),
+            target: vec![],
+            mode: None,
+            uid: None,
+            gid: None,
+            mtime: None,
+            link_id: String::new(),
+            xattrs: BTreeMap::new(),
+            base: String::new(),
             hashes: vec![],
             write_allow_list: parent_block.block.write_allow_list.clone(),
-        };
+        }
+    }
 
+    fn put_block(&mut self, uid: u32, prev_hash: String, inode_block: INodeBlock) -> Result<(), Box<dyn Error + Send + Sync>> {
         let block = DataCapsuleFileSystemBlock {
-            prev_hash: self.get_inode(parent_ino).0.hash,
+            prev_hash,
             block: Some(Block::Inode(inode_block)),
             updated_by: Some(self.middleware_client.clone().unwrap().get_id(uid as u64)),
             signature: vec![],
         };
         // block.sign(self.signing_key.as_ref().unwrap());
-        let response = block_on(self.middleware_client.clone().unwrap().put_inode(block.clone())).unwrap();
+        let response = block_on(self.middleware_client.clone().unwrap().put_inode(block))?;
         self.resolve_block(response.hash.unwrap(), response.block.unwrap());
+        Ok(())
+    }
+
+    /* Publishes a new version of the entry under the new parent and name. It refers to the version
+     * it was moved from, so resolving it takes the entry out of its old parent and keeps the ino.
+     * An existing entry with the new name is replaced, as the new version is the latest.
+     */
+    pub fn rename(&mut self, uid: u32, entry: &INode, new_parent_ino: u64, new_name: &OsStr) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let entry = self.materialize(uid, entry.clone())?;
+        let inode_block = self.moved_block(&entry, new_name);
+        self.put_block(uid, self.get_inode(new_parent_ino).0.hash, inode_block)
+    }
+
+    fn moved_block(&self, entry: &INode, new_name: &OsStr) -> INodeBlock {
+        let mut inode_block = entry.block.clone();
+        if !inode_block.link_id.is_empty() {
+            inode_block = self.get_inode(entry.ino).0.block; // the entry may not have been updated last
+        }
+        inode_block.filename = Vec::from(new_name.as_bytes());
+        inode_block.moved_from = entry.hash.clone();
+        inode_block.base = entry.hash.clone();
+        return inode_block;
+    }
+
+    // number of entries of the file, directories have 2 for themselves and their "."
+    pub fn nlink(&self, ino: u64) -> u32 {
+        let inode = &self.inodes[ino as usize].0;
+        return if inode.block.kind == Kind::Directory.into() {
+            2
+        } else if inode.block.link_id.is_empty() {
+            1
+        } else {
+            self.live_entries(ino).len() as u32
+        }
+    }
+
+    // the entries of ino that are not deleted, a hard linked file can have several
+    fn live_entries(&self, ino: u64) -> Vec<INode> {
+        let Some(parents) = self.link_parents.get(&ino) else {
+            return vec![];
+        };
+        return parents.iter()
+            .flat_map(|parent| self.inodes[*parent as usize].1.iter())
+            .filter(|x| x.ino == ino && !x.is_deleted())
+            .cloned()
+            .collect();
+    }
+
+    // whether ino is the ancestor itself or one of its descendants
+    pub fn is_ancestor(&self, ancestor: u64, mut ino: u64) -> bool {
+        loop {
+            if ino == ancestor {
+                return true;
+            }
+            if ino == 1 {
+                return false;
+            }
+            ino = self.get_ino(self.get_inode(ino).0.parent_hash);
+        }
+    }
+
+    // usage of the inode and the data capsule
+    pub fn get_stats(&self) -> Result<(StatsResponse, StatsResponse), Box<dyn Error>> {
+        Ok((self.inode_client.get_stats()?, self.block_client.get_stats()?))
+    }
+
+    // in the order the versions were resolved, which is not necessarily the order they were written in
+    pub fn get_history(&self, ino: u64) -> Vec<(INode, u64)> {
+        return self.history.get(&ino).cloned().unwrap_or_default();
+    }
+
+    pub fn block_size(&self) -> usize {
+        return self.block_size;
     }
 
     pub fn num_inodes(&self) -> u64 {
@@ -142,20 +470,199 @@ impl Cache {
         return *self.hash_to_ino.get(&hash).unwrap();
     }
 
-    fn resolve(&mut self, hash: String) {
-        if !self.hash_to_ino.contains_key(&hash) {
-            let block = block_on(self.inode_client.get(hash.clone())).unwrap();
-            self.resolve_block(hash, block)
+    /* Returns whether the block is now part of the tree. The versions it was made from are resolved
+     * first, oldest first, so that long histories do not recurse through resolve_block.
+     */
+    fn resolve(&mut self, hash: String) -> bool {
+        let mut chain = vec![];
+        let mut seen = HashSet::new();
+        let mut next = hash.clone();
+        while !next.is_empty() && !self.hash_to_ino.contains_key(&next) && seen.insert(next.clone()) {
+            match block_on(self.inode_client.get(next.clone())) {
+                Ok(block) => {
+                    let from = match block.fs.as_ref().and_then(|x| x.block.as_ref()) {
+                        Some(Block::Inode(data)) if data.base.is_empty() => data.moved_from.clone(),
+                        Some(Block::Inode(data)) => data.base.clone(),
+                        _ => String::new(),
+                    };
+                    chain.push((next, block));
+                    next = from;
+                }
+                Err(e) => {
+                    warn!("Unable to get block {}: {}", next, e);
+                    break;
+                }
+            }
+        }
+        for (hash, block) in chain.into_iter().rev() {
+            if !self.hash_to_ino.contains_key(&hash) {
+                self.resolve_block(hash, block);
+            }
         }
+        return self.hash_to_ino.contains_key(&hash);
     }
 
-    fn resolve_block(&mut self, hash: String, block: DataCapsuleBlock) {
-        if !self.hash_to_ino.contains_key(&block.prev_hash) {
-            self.resolve(block.prev_hash.clone());
+    // like resolve, but fetches the blocks of all hashes and their missing ancestors level by level in batches
+    fn resolve_all(&mut self, hashes: Vec<String>) {
+        let mut blocks = HashMap::new();
+        let mut pending: HashSet<String> = hashes.iter().filter(|x| !self.hash_to_ino.contains_key(*x)).cloned().collect();
+        while !pending.is_empty() {
+            let fetched = match block_on(self.inode_client.get_many(pending.into_iter().collect())) {
+                Ok(fetched) => fetched,
+                Err(e) => {
+                    warn!("Unable to get blocks: {}", e);
+                    break; // the blocks fetched so far are still resolved
+                }
+            };
+            pending = HashSet::new();
+            for (hash, block) in fetched {
+                let prev_hash = block.prev_hash.clone();
+                blocks.insert(hash, block);
+                if !self.hash_to_ino.contains_key(&prev_hash) && !blocks.contains_key(&prev_hash) {
+                    pending.insert(prev_hash);
+                }
+            }
+        }
+
+        for hash in hashes {
+            // resolve ancestors first
+            let mut chain = vec![];
+            let mut next = hash;
+            while let Some(block) = blocks.get(&next) {
+                if self.hash_to_ino.contains_key(&next) {
+                    break;
+                }
+                chain.push((next.clone(), block.clone()));
+                next = block.prev_hash.clone();
+            }
+            for (hash, block) in chain.into_iter().rev() {
+                if !self.hash_to_ino.contains_key(&hash) {
+                    self.resolve_block(hash, block);
+                }
+            }
         }
+    }
 
-        if let Block::Inode(data) = block.fs.unwrap().block.unwrap() {
-            let parent_ino = self.hash_to_ino.get(&block.prev_hash).unwrap();
+    // the parent was resolved before, so its block is usually still cached by the client
+    fn is_allowed_writer(&self, block: &DataCapsuleBlock) -> bool {
+        let Ok(parent) = block_on(self.inode_client.get(block.prev_hash.clone())) else {
+            return false;
+        };
+        return block.fs.as_ref().zip(parent.fs.as_ref()).is_some_and(|(fs, parent)| is_allowed_writer(fs, parent));
+    }
+
+    // takes the entry of ino that version from belongs to out of its parent, if it is still there
+    fn move_out(&mut self, ino: u64, from: &str, timestamp: i64) {
+        // every resolved version is in the history of its ino
+        let Some((version, _)) = self.history.get(&ino).and_then(|x| x.iter().find(|(x, _)| x.hash == from)) else {
+            return;
+        };
+        let filename = version.block.filename.clone();
+        let Some(parent_ino) = self.hash_to_ino.get(&version.parent_hash).copied() else {
+            return;
+        };
+        let children = &mut self.inodes[parent_ino as usize].1;
+        if let Some(idx) = children.iter().position(|x| x.ino == ino && x.block.filename == filename) {
+            children.remove(idx);
+            self.changes.push(Change { parent: parent_ino, name: filename.clone(), ino });
+            self.moved_away.insert((parent_ino, filename), (timestamp, ino));
+        }
+    }
+
+    // whether hash is the version ancestor or was made by changing it, directly or through other versions
+    fn derives_from<'a>(&'a self, mut hash: &'a str, ancestor: &str) -> bool {
+        let Some((_, oldest, _)) = self.versions.get(ancestor) else {
+            return false;
+        };
+        while hash != ancestor {
+            match self.versions.get(hash) {
+                Some((base, timestamp, _)) if timestamp >= oldest => hash = base,
+                _ => return false,
+            }
+        }
+        return true;
+    }
+
+    /* Whether two versions of a file were written concurrently, neither writer having seen the
+     * other's version. Versions without a base, such as those written before bases were recorded,
+     * and renames replacing an entry are never conflicts.
+     */
+    fn is_conflict(&self, winner: &INode, loser: &INode) -> bool {
+        // a write is kept as a conflict when a concurrent delete wins, not the other way around
+        return (winner.block.kind == Kind::RegularFile.into() || winner.block.kind == Kind::DeletedRegularFile.into())
+            && loser.block.kind == Kind::RegularFile.into()
+            && winner.block.moved_from.is_empty()
+            && !winner.block.base.is_empty()
+            && !self.derives_from(&winner.hash, &loser.hash);
+    }
+
+    /* Shows a version that lost to a concurrent one next to the file, as
+     * <name>.conflict-<uid of its author>-<start of its hash>.
+     */
+    fn add_conflict(&mut self, parent_ino: usize, loser: INode) {
+        let uid = self.versions.get(&loser.hash).map_or(0, |x| x.2);
+        let mut entry = loser;
+        entry.block.filename.extend_from_slice(format!(".conflict-{}-{}", uid, &entry.hash[..min(8, entry.hash.len())]).as_bytes());
+        entry.block.link_id = String::new(); // a copy, not another entry of the file
+        // already there, replaced by a version published under that name, or deleted by the user
+        let exists = self.inodes[parent_ino].1.iter()
+            .any(|x| x.block.filename == entry.block.filename && (!x.is_deleted() || x.block.base == entry.hash));
+        if exists {
+            return;
+        }
+        let file_ino = entry.ino;
+        entry.ino = self.inodes.len() as u64;
+        entry.created = entry.timestamp;
+        self.inodes.push((entry.clone(), Vec::new()));
+        self.inodes[parent_ino].1.push(entry.clone());
+        self.changes.push(Change { parent: parent_ino as u64, name: entry.block.filename.clone(), ino: entry.ino });
+        self.conflicts.insert(entry.ino, (file_ino, entry.hash.clone()));
+        self.history.entry(entry.ino).or_default().push((entry, uid));
+    }
+
+    // conflict entries of versions the latest version was made from are resolved
+    fn drop_conflicts(&mut self, winner: &INode) {
+        let resolved: Vec<(u64, String)> = self.conflicts.iter()
+            .filter(|(_, (file_ino, hash))| *file_ino == winner.ino && self.derives_from(&winner.hash, hash))
+            .map(|(ino, (_, hash))| (*ino, hash.clone()))
+            .collect();
+        for (ino, hash) in resolved {
+            self.conflicts.remove(&ino);
+            for (parent, (_, children)) in self.inodes.iter_mut().enumerate() {
+                if let Some(idx) = children.iter().position(|x| x.ino == ino && x.hash == hash) {
+                    let entry = children.remove(idx);
+                    self.changes.push(Change { parent: parent as u64, name: entry.block.filename, ino });
+                }
+            }
+        }
+    }
+
+    // only blocks whose prev_hash chain leads to the root are accepted, returns whether the block is now part of the tree
+    fn resolve_block(&mut self, hash: String, block: DataCapsuleBlock) -> bool {
+        if self.cutoff.is_some_and(|cutoff| block.timestamp > cutoff) && !self.ancestry.contains(&hash) {
+            return false;
+        }
+        if block.prev_hash.is_empty() || !self.resolve(block.prev_hash.clone()) {
+            warn!("Ignoring block {} that is not chained to the root", hash);
+            return false;
+        }
+        if self.inode_client.is_crypto_enabled() && !self.is_allowed_writer(&block) {
+            warn!("Ignoring block {} whose author may not write to its parent", hash);
+            return false;
+        }
+
+        let fs = block.fs.unwrap();
+        let uid = fs.updated_by.map_or(0, |x| x.uid);
+        if let Block::Inode(data) = fs.block.unwrap() {
+            let parent_ino = *self.hash_to_ino.get(&block.prev_hash).unwrap() as usize;
+            if !self.ancestry.is_empty() {
+                let entry = (parent_ino as u64, data.filename.clone());
+                if self.ancestry.contains(&hash) {
+                    self.cutoff_entries.insert(entry);
+                } else if self.cutoff_entries.contains(&entry) {
+                    return false; // a version the writer of the cutoff block had not seen
+                }
+            }
 
             let mut inode = INode {
                 hash: hash.clone(),
@@ -163,6 +670,7 @@ impl Cache {
                 parent_hash: block.prev_hash,
                 block: data,
                 timestamp: block.timestamp,
+                created: block.timestamp,
                 block_client: self.block_client.clone(),
                 middleware_client: self.middleware_client.clone(),
                 journal: HashMap::new(),
@@ -170,26 +678,394 @@ impl Cache {
                 block_size: self.block_size
             };
 
+            self.versions.insert(hash.clone(), (inode.block.base.clone(), inode.timestamp, uid));
+            if !inode.block.base.is_empty() {
+                self.resolve(inode.block.base.clone()); // needed to tell whether it conflicts with the current version
+            }
+
+            // a renamed entry keeps its ino, so its children and open files follow it
+            let mut ino = None;
+            if !inode.block.moved_from.is_empty() && self.resolve(inode.block.moved_from.clone()) {
+                let moved_ino = self.get_ino(inode.block.moved_from.clone());
+                self.move_out(moved_ino, &inode.block.moved_from, inode.timestamp);
+                ino = Some(moved_ino);
+            }
 
-            let index = self.inodes.get(*parent_ino as usize).unwrap().1.iter().position(|x| x.block.filename == inode.block.filename);
+            let mut recreated = false;
+            let mut loser = None;
+            let index = self.inodes[parent_ino].1.iter().position(|x| x.block.filename == inode.block.filename);
             if let Some(idx) = index {
-                {
-                    let prev_node = self.inodes.get(*parent_ino as usize).unwrap().1.get(idx).unwrap();
-                    if prev_node.timestamp > inode.timestamp {
-                        return; // we're having an older node, discard
+                let prev_node = &self.inodes[parent_ino].1[idx];
+                let prev_ino = prev_node.ino;
+                // the hash breaks ties, so every client picks the same version
+                if (prev_node.timestamp, &prev_node.hash) > (inode.timestamp, &inode.hash) {
+                    // we're having an older node, discard, but its children still belong to the same inode
+                    self.hash_to_ino.insert(hash, prev_ino);
+                    let conflict = self.is_conflict(prev_node, &inode);
+                    if !inode.is_deleted() && inode.timestamp < prev_node.created {
+                        self.inodes[parent_ino].1[idx].created = inode.timestamp;
+                        self.inodes[prev_ino as usize].0.created = inode.timestamp;
+                    }
+                    inode.ino = prev_ino;
+                    if conflict {
+                        self.add_conflict(parent_ino, inode.clone());
+                    }
+                    self.history.entry(prev_ino).or_default().push((inode, uid));
+                    return true;
+                }
+                if self.is_conflict(&inode, prev_node) {
+                    loser = Some(prev_node.clone());
+                }
+                // a deleted hard link does not pass its ino on to a new file with the same name
+                if ino.is_none() && !(prev_node.is_deleted() && prev_node.block.link_id != inode.block.link_id) {
+                    ino = Some(prev_ino);
+                    if prev_node.is_deleted() {
+                        self.inodes[prev_ino as usize].1 = Vec::new(); // recreated, the old children are gone
+                        recreated = true;
                     }
-                    inode.ino = prev_node.ino;
                 }
 
-                self.inodes[inode.ino as usize] = (inode.clone(), Vec::new()); // update local inode to the latest version
-                self.inodes.get_mut(*parent_ino as usize).unwrap().1.remove(idx);  // delete outdated inode from parent
+                self.inodes[parent_ino].1.remove(idx);  // delete outdated inode from parent
+            } else if ino.is_none() {
+                if let Some((timestamp, moved_ino)) = self.moved_away.get(&(parent_ino as u64, inode.block.filename.clone())).copied() {
+                    if timestamp > inode.timestamp {
+                        // an older version of an entry that has been renamed since
+                        self.hash_to_ino.insert(hash, moved_ino);
+                        inode.ino = moved_ino;
+                        self.history.entry(moved_ino).or_default().push((inode, uid));
+                        return true;
+                    }
+                }
+            }
+            // all entries of a hard linked file share one ino
+            if let Some(link_ino) = self.link_ino.get(&inode.block.link_id) {
+                ino = Some(*link_ino);
+            }
+
+            if let Some(ino) = ino {
+                inode.ino = ino;
+                if !recreated {
+                    inode.created = self.inodes[ino as usize].0.created;
+                }
             } else {
+                inode.ino = self.inodes.len() as u64; // resolving moved_from may have added inodes
                 self.inodes.push((inode.clone(), Vec::new()));
             }
-            self.inodes.get_mut(*parent_ino as usize).unwrap().1.push(inode.clone());
+            if !inode.block.link_id.is_empty() {
+                self.link_ino.insert(inode.block.link_id.clone(), inode.ino);
+                self.link_parents.entry(inode.ino).or_default().insert(parent_ino as u64);
+            }
+            self.inodes[parent_ino].1.push(inode.clone());
+            // the file is only gone when its last entry is deleted, and has the latest version of any entry
+            let current = &self.inodes[inode.ino as usize].0;
+            let latest = inode.block.link_id.is_empty() || (inode.timestamp, &inode.hash) >= (current.timestamp, &current.hash);
+            if latest && (!inode.is_deleted() || inode.block.link_id.is_empty() || self.live_entries(inode.ino).is_empty()) {
+                self.inodes[inode.ino as usize].0 = inode.clone(); // update local inode to the latest version
+            }
             self.hash_to_ino.insert(hash, inode.ino);
+            self.changes.push(Change { parent: parent_ino as u64, name: inode.block.filename.clone(), ino: inode.ino });
+            self.drop_conflicts(&inode);
+            if let Some(loser) = loser {
+                self.add_conflict(parent_ino, loser);
+            }
+            self.history.entry(inode.ino).or_default().push((inode, uid));
+            true
         } else {
-            panic!();
+            warn!("Ignoring block {} that is not an inode", hash);
+            false
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use tonic::transport::ClientTlsConfig;
+
+    use crate::crypto::SignableBlock;
+    use crate::proto::block::Id;
+    use crate::test_util::verifying_key;
+    use super::*;
+
+    const ROOT: &str = "root";
+
+    // a tree with only the root, the tests resolve blocks whose ancestors are resolved, so the servers are never asked
+    fn test_cache() -> Cache {
+        let block_client = Arc::new(BlockClient::connect(&[], ClientTlsConfig::new(), 1, verifying_key(), false));
+        let root = INode {
+            hash: ROOT.into(),
+            ino: 1,
+            parent_hash: ROOT.into(),
+            block: INodeBlock { kind: Kind::Directory.into(), ..Default::default() },
+            timestamp: 0,
+            created: 0,
+            block_client: block_client.clone(),
+            middleware_client: None,
+            journal: HashMap::new(),
+            prev_data_hash: String::new(),
+            block_size: 4096,
+        };
+        let (_, updates) = channel();
+        Cache {
+            inode_client: INodeClient::connect(&[], ClientTlsConfig::new(), 1, verifying_key(), false),
+            block_client,
+            middleware_client: None,
+            inodes: vec![(root.clone(), Vec::new()), (root, Vec::new())],
+            hash_to_ino: HashMap::from([(ROOT.to_string(), 1)]),
+            moved_away: HashMap::new(),
+            link_ino: HashMap::new(),
+            link_parents: HashMap::new(),
+            history: HashMap::new(),
+            versions: HashMap::new(),
+            conflicts: HashMap::new(),
+            updates,
+            data_root: String::new(),
+            block_size: 4096,
+            cutoff: None,
+            ancestry: HashSet::new(),
+            cutoff_entries: HashSet::new(),
+            changes: Vec::new(),
+        }
+    }
+
+    fn block(prev_hash: &str, inode: INodeBlock, timestamp: i64, uid: u64) -> (String, DataCapsuleBlock) {
+        let block = DataCapsuleBlock {
+            prev_hash: prev_hash.into(),
+            fs: Some(DataCapsuleFileSystemBlock {
+                prev_hash: prev_hash.into(),
+                block: Some(Block::Inode(inode)),
+                updated_by: Some(Id { uid, ..Default::default() }),
+                signature: vec![],
+            }),
+            timestamp,
+            signature: vec![],
+        };
+        (block.hash(), block)
+    }
+
+    fn publish(cache: &mut Cache, prev_hash: &str, inode: INodeBlock, timestamp: i64) -> String {
+        let (hash, block) = block(prev_hash, inode, timestamp, 1000);
+        assert!(cache.resolve_block(hash.clone(), block));
+        hash
+    }
+
+    fn file(name: &str, base: &str, size: u64) -> INodeBlock {
+        INodeBlock { filename: name.into(), base: base.into(), size, ..Default::default() }
+    }
+
+    fn deleted(mut inode: INodeBlock) -> INodeBlock {
+        inode.kind = Kind::DeletedRegularFile.into();
+        inode
+    }
+
+    fn child(cache: &Cache, parent: u64, name: &str) -> Option<INode> {
+        cache.find_child_node(parent, OsStr::new(name))
+    }
+
+    fn names(cache: &Cache, parent: u64) -> Vec<String> {
+        let mut names: Vec<String> = cache.get_inode(parent).1.iter()
+            .filter(|x| !x.is_deleted())
+            .map(|x| String::from_utf8(x.block.filename.clone()).unwrap())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn concurrent_writes_keep_the_loser() {
+        for reversed in [false, true] {
+            let mut cache = test_cache();
+            let first = publish(&mut cache, ROOT, file("a", "", 0), 1);
+            let mut writes = vec![block(ROOT, file("a", &first, 1), 2, 1001), block(ROOT, file("a", &first, 2), 3, 1002)];
+            let loser = writes[0].0.clone();
+            if reversed {
+                writes.reverse();
+            }
+            for (hash, block) in writes {
+                assert!(cache.resolve_block(hash, block));
+            }
+
+            let conflict = format!("a.conflict-1001-{}", &loser[..8]);
+            assert_eq!(names(&cache, 1), vec!["a".to_string(), conflict.clone()]);
+            assert_eq!(child(&cache, 1, "a").unwrap().block.size, 2);
+            let conflict = child(&cache, 1, &conflict).unwrap();
+            assert_eq!(conflict.block.size, 1);
+            assert_ne!(conflict.ino, child(&cache, 1, "a").unwrap().ino);
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn equal_timestamps_pick_the_same_version() {
+        let mut winners = vec![];
+        for reversed in [false, true] {
+            let mut cache = test_cache();
+            let first = publish(&mut cache, ROOT, file("a", "", 0), 1);
+            let mut writes = vec![block(ROOT, file("a", &first, 1), 2, 1001), block(ROOT, file("a", &first, 2), 2, 1002)];
+            let expected = if writes[0].0 > writes[1].0 { 1 } else { 2 };
+            if reversed {
+                writes.reverse();
+            }
+            for (hash, block) in writes {
+                cache.resolve_block(hash, block);
+            }
+            assert_eq!(child(&cache, 1, "a").unwrap().block.size, expected);
+            winners.push(child(&cache, 1, "a").unwrap().hash);
+        }
+        assert_eq!(winners[0], winners[1]);
+    }
+
+    #[test]
+    fn sequential_writes_do_not_conflict() {
+        let mut cache = test_cache();
+        let first = publish(&mut cache, ROOT, file("a", "", 0), 1);
+        let second = publish(&mut cache, ROOT, file("a", &first, 1), 2);
+        publish(&mut cache, ROOT, file("a", &second, 2), 3);
+        // written by a client that does not record bases
+        publish(&mut cache, ROOT, file("a", "", 3), 4);
+        assert_eq!(names(&cache, 1), vec!["a"]);
+        assert_eq!(child(&cache, 1, "a").unwrap().block.size, 3);
+        assert_eq!(cache.get_history(child(&cache, 1, "a").unwrap().ino).len(), 4);
+    }
+
+    #[test]
+    fn merged_conflict_disappears() {
+        let mut cache = test_cache();
+        let conflict = |hash: &str| format!("a.conflict-1000-{}", &hash[..8]);
+        let first = publish(&mut cache, ROOT, file("a", "", 0), 1);
+        let loser = publish(&mut cache, ROOT, file("a", &first, 1), 2);
+        let winner = publish(&mut cache, ROOT, file("a", &first, 2), 3);
+        assert_eq!(names(&cache, 1), vec!["a".to_string(), conflict(&loser)]);
+
+        // a version made from the loser resolves its conflict, the winner it was not made from becomes one
+        publish(&mut cache, ROOT, file("a", &loser, 3), 4);
+        assert_eq!(names(&cache, 1), vec!["a".to_string(), conflict(&winner)]);
+        assert_eq!(child(&cache, 1, "a").unwrap().block.size, 3);
+    }
+
+    #[test]
+    fn write_losing_to_a_delete_is_kept() {
+        let mut cache = test_cache();
+        let first = publish(&mut cache, ROOT, file("a", "", 0), 1);
+        publish(&mut cache, ROOT, deleted(file("a", &first, 0)), 3);
+        let write = publish(&mut cache, ROOT, file("a", &first, 1), 2);
+        assert_eq!(names(&cache, 1), vec![format!("a.conflict-1000-{}", &write[..8])]);
+    }
+
+    #[test]
+    fn delete_losing_to_a_write_is_dropped() {
+        let mut cache = test_cache();
+        let first = publish(&mut cache, ROOT, file("a", "", 0), 1);
+        publish(&mut cache, ROOT, file("a", &first, 1), 3);
+        publish(&mut cache, ROOT, deleted(file("a", &first, 0)), 2);
+        assert_eq!(names(&cache, 1), vec!["a"]);
+    }
+
+    fn moved(cache: &Cache, entry: &INode, name: &str) -> INodeBlock {
+        cache.moved_block(entry, OsStr::new(name))
+    }
+
+    #[test]
+    fn root_is_never_updated() {
+        let mut cache = test_cache();
+        let block = INodeBlock { mode: Some(0o755), ..cache.get_inode(1).0.block };
+        assert!(cache.update(1000, 1, block).is_err());
+        assert!(cache.get_inode(1).1.is_empty());
+        assert_eq!(cache.get_inode(1).0.block.mode, None);
+    }
+
+    #[test]
+    fn rename_keeps_the_ino() {
+        let mut cache = test_cache();
+        let dir = publish(&mut cache, ROOT, INodeBlock { filename: "d".into(), kind: Kind::Directory.into(), ..Default::default() }, 1);
+        publish(&mut cache, ROOT, file("a", "", 3), 2);
+        let entry = child(&cache, 1, "a").unwrap();
+        let block = moved(&cache, &entry, "b");
+        publish(&mut cache, &dir, block, 3);
+
+        let dir_ino = cache.get_ino(dir);
+        assert_eq!(names(&cache, 1), vec!["d"]);
+        assert_eq!(names(&cache, dir_ino), vec!["b"]);
+        let renamed = child(&cache, dir_ino, "b").unwrap();
+        assert_eq!(renamed.ino, entry.ino);
+        assert_eq!(renamed.block.size, 3);
+        assert_eq!(cache.get_inode(entry.ino).0.hash, renamed.hash);
+    }
+
+    #[test]
+    fn rename_replaces_the_target() {
+        let mut cache = test_cache();
+        publish(&mut cache, ROOT, file("a", "", 1), 1);
+        let target = publish(&mut cache, ROOT, file("b", "", 2), 2);
+        let entry = child(&cache, 1, "a").unwrap();
+        publish(&mut cache, ROOT, deleted(file("b", &target, 0)), 3);
+        let block = moved(&cache, &entry, "b");
+        publish(&mut cache, ROOT, block, 4);
+
+        assert_eq!(names(&cache, 1), vec!["b"]);
+        let replaced = child(&cache, 1, "b").unwrap();
+        assert_eq!(replaced.ino, entry.ino);
+        assert_eq!(replaced.block.size, 1);
+    }
+
+    #[test]
+    fn late_version_of_a_renamed_entry() {
+        let mut cache = test_cache();
+        let first = publish(&mut cache, ROOT, file("a", "", 0), 1);
+        let entry = child(&cache, 1, "a").unwrap();
+        let (late, late_block) = block(ROOT, file("a", &first, 1), 2, 1000);
+        let block = moved(&cache, &entry, "b");
+        publish(&mut cache, ROOT, block, 3);
+        assert!(cache.resolve_block(late.clone(), late_block));
+
+        assert_eq!(names(&cache, 1), vec!["b"]);
+        assert_eq!(cache.get_ino(late), entry.ino);
+        assert_eq!(cache.get_history(entry.ino).len(), 3);
+    }
+
+    // a and b in the root are entries of the same file
+    fn linked(cache: &mut Cache) -> (String, String) {
+        let first = publish(cache, ROOT, file("a", "", 1), 1);
+        let link = INodeBlock { link_id: first.clone(), ..file("a", &first, 1) };
+        let a = publish(cache, ROOT, link.clone(), 2);
+        let b = publish(cache, ROOT, INodeBlock { filename: "b".into(), base: String::new(), ..link }, 3);
+        (a, b)
+    }
+
+    #[test]
+    fn links_share_the_ino() {
+        let mut cache = test_cache();
+        let (a, _) = linked(&mut cache);
+        let ino = child(&cache, 1, "a").unwrap().ino;
+        assert_eq!(child(&cache, 1, "b").unwrap().ino, ino);
+        assert_eq!(cache.nlink(ino), 2);
+
+        let entry = child(&cache, 1, "a").unwrap();
+        publish(&mut cache, ROOT, INodeBlock { kind: Kind::DeletedRegularFile.into(), base: a, ..entry.block }, 4);
+        assert_eq!(names(&cache, 1), vec!["b"]);
+        assert_eq!(cache.nlink(ino), 1);
+        assert!(!cache.get_inode(ino).0.is_deleted());
+
+        let entry = child(&cache, 1, "b").unwrap();
+        publish(&mut cache, ROOT, INodeBlock { kind: Kind::DeletedRegularFile.into(), base: entry.hash, ..entry.block }, 5);
+        assert!(cache.get_inode(ino).0.is_deleted());
+    }
+
+    #[test]
+    fn linked_file_is_updated_through_one_entry() {
+        let mut cache = test_cache();
+        let (a, b) = linked(&mut cache);
+        let ino = child(&cache, 1, "a").unwrap().ino;
+        let a_entry = child(&cache, 1, "a").unwrap();
+
+        // the entry the change was made through, otherwise the one of the latest version
+        let (entry, base) = cache.update_entry(ino, Some(&a_entry), true);
+        assert_eq!((entry.block.filename, base), (b"a".to_vec(), a.clone()));
+        let (entry, base) = cache.update_entry(ino, None, true);
+        assert_eq!((entry.block.filename, base), (b"b".to_vec(), b.clone()));
+
+        // every entry shows the latest version, a rename of a stale entry keeps it
+        let block = INodeBlock { size: 5, base: b, ..child(&cache, 1, "b").unwrap().block };
+        publish(&mut cache, ROOT, block, 4);
+        assert_eq!(cache.get_inode(ino).0.block.size, 5);
+        let block = moved(&cache, &a_entry, "c");
+        assert_eq!((block.size, block.moved_from, block.filename), (5, a, b"c".to_vec()));
+    }
+}
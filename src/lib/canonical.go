@@ -0,0 +1,128 @@
+package lib
+
+import (
+	"bytes"
+	"cfs/middleware/src/lib/go_proto"
+	"encoding/binary"
+	"sort"
+)
+
+// Deterministic encoding that is signed instead of the protobuf serialization, which differs
+// between Go and prost. Must be kept in sync with the Canonical trait in src/lib/crypto.rs.
+
+func canonicalBytes(buf *bytes.Buffer, data []byte) {
+	canonicalUint64(buf, uint64(len(data)))
+	buf.Write(data)
+}
+
+func canonicalUint64(buf *bytes.Buffer, value uint64) {
+	var b [8]byte
+	binary.BigEndian.PutUint64(b[:], value)
+	buf.Write(b[:])
+}
+
+func canonicalOptionalUint32(buf *bytes.Buffer, value *uint32) {
+	if value != nil {
+		buf.WriteByte(1)
+		canonicalUint64(buf, uint64(*value))
+	} else {
+		buf.WriteByte(0)
+	}
+}
+
+func canonicalID(buf *bytes.Buffer, id *go_proto.ID) {
+	canonicalBytes(buf, id.PubKey)
+	canonicalUint64(buf, id.Uid)
+	canonicalBytes(buf, id.Signature)
+}
+
+func canonicalDataBlock(buf *bytes.Buffer, data *go_proto.DataBlock) {
+	canonicalBytes(buf, data.Data)
+}
+
+func canonicalINodeBlock(buf *bytes.Buffer, inode *go_proto.INodeBlock) {
+	canonicalBytes(buf, inode.Filename)
+	canonicalUint64(buf, inode.Size)
+	canonicalUint64(buf, uint64(inode.Kind))
+	canonicalBytes(buf, []byte(inode.MovedFrom))
+	canonicalBytes(buf, inode.Target)
+	canonicalOptionalUint32(buf, inode.Mode)
+	canonicalOptionalUint32(buf, inode.Uid)
+	canonicalOptionalUint32(buf, inode.Gid)
+	if inode.Mtime != nil {
+		buf.WriteByte(1)
+		canonicalUint64(buf, uint64(*inode.Mtime))
+	} else {
+		buf.WriteByte(0)
+	}
+	canonicalBytes(buf, []byte(inode.LinkId))
+	names := make([]string, 0, len(inode.Xattrs))
+	for name := range inode.Xattrs {
+		names = append(names, name)
+	}
+	sort.Strings(names)
+	canonicalUint64(buf, uint64(len(names)))
+	for _, name := range names {
+		canonicalBytes(buf, []byte(name))
+		canonicalBytes(buf, inode.Xattrs[name])
+	}
+	canonicalBytes(buf, []byte(inode.Base))
+	canonicalUint64(buf, uint64(len(inode.Hashes)))
+	for _, hash := range inode.Hashes {
+		canonicalBytes(buf, []byte(hash))
+	}
+	canonicalUint64(buf, uint64(len(inode.WriteAllowList)))
+	for _, id := range inode.WriteAllowList {
+		canonicalID(buf, id)
+	}
+}
+
+func canonicalFileSystemBlock(buf *bytes.Buffer, block *go_proto.DataCapsuleFileSystemBlock) {
+	canonicalBytes(buf, []byte(block.PrevHash))
+	if data := block.GetData(); data != nil {
+		buf.WriteByte(1)
+		canonicalDataBlock(buf, data)
+	} else if inode := block.GetInode(); inode != nil {
+		buf.WriteByte(2)
+		canonicalINodeBlock(buf, inode)
+	} else {
+		buf.WriteByte(0)
+	}
+	if block.UpdatedBy != nil {
+		buf.WriteByte(1)
+		canonicalID(buf, block.UpdatedBy)
+	} else {
+		buf.WriteByte(0)
+	}
+	canonicalBytes(buf, block.Signature)
+}
+
+func canonicalDataCapsuleBlock(buf *bytes.Buffer, block *go_proto.DataCapsuleBlock) {
+	canonicalBytes(buf, []byte(block.PrevHash))
+	if block.Fs != nil {
+		buf.WriteByte(1)
+		canonicalFileSystemBlock(buf, block.Fs)
+	} else {
+		buf.WriteByte(0)
+	}
+	canonicalUint64(buf, uint64(block.Timestamp))
+	canonicalBytes(buf, block.Signature)
+}
+
+func CanonicalID(id *go_proto.ID) []byte {
+	var buf bytes.Buffer
+	canonicalID(&buf, id)
+	return buf.Bytes()
+}
+
+func CanonicalFileSystemBlock(block *go_proto.DataCapsuleFileSystemBlock) []byte {
+	var buf bytes.Buffer
+	canonicalFileSystemBlock(&buf, block)
+	return buf.Bytes()
+}
+
+func CanonicalDataCapsuleBlock(block *go_proto.DataCapsuleBlock) []byte {
+	var buf bytes.Buffer
+	canonicalDataCapsuleBlock(&buf, block)
+	return buf.Bytes()
+}
@@ -0,0 +1,54 @@
+package lib
+
+import (
+	"cfs/middleware/src/lib/go_proto"
+	"encoding/hex"
+	"os"
+	"strings"
+	"testing"
+)
+
+// Same block as sample_block in the tests of src/lib/crypto.rs, both must encode to testdata/canonical.hex.
+func TestCanonicalDataCapsuleBlock(t *testing.T) {
+	mode, uid, mtime := uint32(0o644), uint32(1000), int64(-1)
+	id := &go_proto.ID{PubKey: []byte("key"), Uid: 1000, Signature: []byte{1, 2}}
+	block := &go_proto.DataCapsuleBlock{
+		PrevHash: "p",
+		Fs: &go_proto.DataCapsuleFileSystemBlock{
+			PrevHash: "p",
+			Block: &go_proto.DataCapsuleFileSystemBlock_Inode{Inode: &go_proto.INodeBlock{
+				Filename:       []byte("a.txt"),
+				Size:           5,
+				Kind:           go_proto.INodeBlock_Symlink,
+				MovedFrom:      "m",
+				Target:         []byte("t"),
+				Mode:           &mode,
+				Uid:            &uid,
+				Mtime:          &mtime,
+				LinkId:         "l",
+				Xattrs:         map[string][]byte{"user.b": {2}, "user.a": {1}},
+				Base:           "b",
+				Hashes:         []string{"h1", ""},
+				WriteAllowList: []*go_proto.ID{id},
+			}},
+			UpdatedBy: id,
+			Signature: []byte{3},
+		},
+		Timestamp: 42,
+	}
+
+	expected, err := os.ReadFile("testdata/canonical.hex")
+	if err != nil {
+		t.Fatal(err)
+	}
+	if actual := hex.EncodeToString(CanonicalDataCapsuleBlock(block)); actual != strings.TrimSpace(string(expected)) {
+		t.Errorf("got %s", actual)
+	}
+}
+
+func TestCanonicalAbsentFields(t *testing.T) {
+	expected := make([]byte, 18) // empty prev hash, no block, no author, empty signature
+	if actual := CanonicalFileSystemBlock(&go_proto.DataCapsuleFileSystemBlock{}); string(actual) != string(expected) {
+		t.Errorf("got %x", actual)
+	}
+}
@@ -1,43 +1,165 @@
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
 use std::fmt::{Display, Formatter};
 use std::num::NonZeroUsize;
+use std::future::Future;
 use std::str::FromStr;
-use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use duplicate::duplicate_item;
+use log::warn;
 use lru::LruCache;
 use rsa::pkcs1v15::{SigningKey, VerifyingKey};
 use rsa::sha2::Sha256;
 use tokio::runtime::Runtime;
 use tokio::task::JoinHandle;
+use tonic::{Code, Status};
 use tonic::transport::{Channel, ClientTlsConfig, Uri};
 
-use crate::crypto::SignableBlock;
+use crate::crypto::{SignableBlock, validate_file_system_block};
 use crate::proto::block::{DataCapsuleBlock, DataCapsuleFileSystemBlock, Id};
 use crate::proto::block::data_capsule_file_system_block::Block;
-use crate::proto::data_capsule::{GetRequest, LeafsRequest};
+use crate::proto::data_capsule::{BatchGetRequest, GetRequest, LeafsRequest, StatsRequest, StatsResponse, SubscribeRequest};
 use crate::proto::data_capsule::data_capsule_client::DataCapsuleClient;
 use crate::proto::middleware::{PutDataRequest, PutDataResponse, PutINodeRequest, PutINodeResponse};
 use crate::proto::middleware::middleware_client::MiddlewareClient;
+use crate::server::BATCH_GET_LIMIT;
 
 #[derive(Debug, Clone)]
-struct ClientError {}
+struct ClientError {
+    reason: String,
+}
+
+impl ClientError {
+    fn new(reason: impl Into<String>) -> ClientError {
+        ClientError { reason: reason.into() }
+    }
+}
 
 impl Display for ClientError {
-    fn fmt(&self, _f: &mut Formatter<'_>) -> std::fmt::Result {
-        todo!()
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.reason)
     }
 }
 
 impl Error for ClientError {}
 
+const REPLICA_RETRY_ROUNDS: u32 = 3;
+const REPLICA_BACKOFF: Duration = Duration::from_millis(200);
+const REPLICA_COOLDOWN: Duration = Duration::from_secs(30); // how long a failed replica is tried last
+
+/* All replicas of a capsule. Blocks are verified by hash, so any replica's answer is acceptable.
+ * Requests go to the replica that answered last, then fail over to the healthy replicas and
+ * finally to the ones that failed recently.
+ */
+struct Replicas<C> {
+    clients: Vec<(String, C)>,
+    preferred: AtomicUsize,
+    failed_at: Mutex<Vec<Option<Instant>>>,
+}
+
+impl<C: Clone> Replicas<C> {
+    fn new(clients: Vec<(String, C)>) -> Replicas<C> {
+        let failed_at = Mutex::new(vec![None; clients.len()]);
+        Replicas { clients, preferred: AtomicUsize::new(0), failed_at }
+    }
+
+    fn order(&self) -> Vec<usize> {
+        let preferred = self.preferred.load(Ordering::Relaxed);
+        let failed_at = self.failed_at.lock().unwrap();
+        let mut order: Vec<usize> = (0..self.clients.len()).map(|i| (preferred + i) % self.clients.len()).collect();
+        // stable, so the preferred replica stays first among the healthy ones
+        order.sort_by_key(|i| failed_at[*i].is_some_and(|time| time.elapsed() < REPLICA_COOLDOWN));
+        order
+    }
+
+    async fn call<F, Fut, R>(&self, f: F) -> Result<R, Status> where F: Fn(C) -> Fut, Fut: Future<Output = Result<R, Status>> {
+        let mut last_error = Status::unavailable("No replicas configured");
+        for round in 0..REPLICA_RETRY_ROUNDS {
+            if round > 0 {
+                tokio::time::sleep(REPLICA_BACKOFF * round).await;
+            }
+            let mut unavailable = false;
+            for i in self.order() {
+                let (addr, client) = &self.clients[i];
+                match f(client.clone()).await {
+                    Ok(result) => {
+                        self.preferred.store(i, Ordering::Relaxed);
+                        self.failed_at.lock().unwrap()[i] = None;
+                        return Ok(result);
+                    }
+                    // the replica works, it may only lag behind, so the others are asked without marking it failed
+                    Err(e) if e.code() == Code::NotFound => last_error = e,
+                    Err(e) => {
+                        warn!("Request to replica {} failed: {}", addr, e);
+                        self.failed_at.lock().unwrap()[i] = Some(Instant::now());
+                        last_error = e;
+                        unavailable = true;
+                    }
+                }
+            }
+            if !unavailable {
+                break; // every replica answered, asking again does not help
+            }
+        }
+        Err(last_error)
+    }
+
+    /* Like call, for requests about a set of keys that a replica may only partly answer, such as
+     * BatchGet. The keys one replica leaves out are asked from the next one, the keys no replica
+     * knows are left out of the result.
+     */
+    async fn call_partial<F, Fut, R>(&self, keys: Vec<String>, f: F) -> Result<HashMap<String, R>, Status> where F: Fn(C, Vec<String>) -> Fut, Fut: Future<Output = Result<HashMap<String, R>, Status>> {
+        let mut results = HashMap::new();
+        let mut missing = keys;
+        let mut last_error = Status::unavailable("No replicas configured");
+        for round in 0..REPLICA_RETRY_ROUNDS {
+            if round > 0 {
+                tokio::time::sleep(REPLICA_BACKOFF * round).await;
+            }
+            let mut answered = false;
+            for i in self.order() {
+                if missing.is_empty() {
+                    break;
+                }
+                let (addr, client) = &self.clients[i];
+                match f(client.clone(), missing.clone()).await {
+                    Ok(mut response) => {
+                        self.failed_at.lock().unwrap()[i] = None;
+                        answered = true;
+                        missing.retain(|key| match response.remove(key) {
+                            Some(result) => {
+                                results.insert(key.clone(), result);
+                                false
+                            }
+                            None => true,
+                        });
+                    }
+                    Err(e) => {
+                        warn!("Request to replica {} failed: {}", addr, e);
+                        self.failed_at.lock().unwrap()[i] = Some(Instant::now());
+                        last_error = e;
+                    }
+                }
+            }
+            if answered {
+                return Ok(results);
+            }
+        }
+        Err(last_error)
+    }
+}
+
 #[duplicate_item(
 T C;
 [BlockClient] [DataCapsuleClient];
 [INodeClient] [DataCapsuleClient];
 )]
 pub struct T {
-    client: C<tonic::transport::Channel>,
+    replicas: Arc<Replicas<C<tonic::transport::Channel>>>,
     runtime: Runtime,
     cache: Mutex<LruCache<String, DataCapsuleBlock>>,
     verifying_key: VerifyingKey<Sha256>,
@@ -50,23 +172,24 @@ T C;
 [INodeClient] [DataCapsuleClient];
 )]
 impl T {
-    pub fn connect(addr: &str, tls_config: ClientTlsConfig, cache_size: usize, verifying_key: VerifyingKey<Sha256>, enable_crypto: bool) -> T {
+    pub fn connect(addrs: &[String], tls_config: ClientTlsConfig, cache_size: usize, verifying_key: VerifyingKey<Sha256>, enable_crypto: bool) -> T {
         let runtime = tokio::runtime::Builder::new_multi_thread()
             .enable_all()
             .build()
             .unwrap();
 
-        let channel = runtime.block_on(async {
-            return Channel::builder(Uri::from_str(addr).unwrap())
-                .tls_config(tls_config).unwrap()
-                .connect()
-                .await;
-        }).unwrap();
-
-        let client = C::new(channel);
+        // connect lazily, so that replicas which are down do not stop the client from starting
+        let clients: Vec<(String, C<Channel>)> = runtime.block_on(async {
+            addrs.iter().map(|addr| {
+                let channel = Channel::builder(Uri::from_str(addr).unwrap())
+                    .tls_config(tls_config.clone()).unwrap()
+                    .connect_lazy();
+                (addr.clone(), C::new(channel))
+            }).collect()
+        });
 
         return T {
-            client,
+            replicas: Arc::new(Replicas::new(clients)),
             runtime,
             cache: Mutex::new(LruCache::new(NonZeroUsize::new(cache_size).unwrap())),
             verifying_key,
@@ -78,51 +201,239 @@ impl T {
 #[duplicate_item(T; [BlockClient]; [INodeClient])]
 impl T {
     pub async fn get(&self, hash: String) -> Result<DataCapsuleBlock, Box<dyn Error + Send + Sync>> {
-        let mut cache = self.cache.lock().unwrap();
+        let cached = self.cache.lock().unwrap().get(&hash).cloned();
 
-        return if let Some(block) = cache.get(&hash) {
-            Ok(block.clone())
+        return if let Some(block) = cached {
+            Ok(block)
         } else {
-            drop(cache);
-
-            let mut client = self.client.clone();
-            let request = tonic::Request::new(GetRequest {
-                block_hash: hash.to_string()
-            });
+            let replicas = self.replicas.clone();
+            let block_hash = hash.to_string();
 
             let handle: JoinHandle<Result<DataCapsuleBlock, Box<dyn Error + Send + Sync>>> = self.runtime.spawn(async move {
-                let response = client.get(request).await?;
-                let block = response.get_ref().clone().block.unwrap();
+                let block = replicas.call(|mut client| {
+                    let request = GetRequest {
+                        block_hash: block_hash.clone()
+                    };
+                    async move {
+                        // a lagging replica may not have the block yet, ask the others
+                        client.get(request).await?.into_inner().block.ok_or(Status::not_found("Unknown block"))
+                    }
+                }).await?;
                 Ok(block)
             });
 
-            let mut block = handle.await??;
-            if !self.enable_crypto || block.validate(&self.verifying_key) {
-                self.cache.lock().unwrap().put(hash.to_string(), block.clone());
-                Ok(block)
-            } else {
-                Err(Box::new(ClientError{}))
+            let block = handle.await??;
+            self.accept(&hash, block)
+        }
+    }
+
+    // fetches all blocks that are not cached in batches, blocks unknown to every replica are left out
+    pub async fn get_many(&self, hashes: Vec<String>) -> Result<HashMap<String, DataCapsuleBlock>, Box<dyn Error + Send + Sync>> {
+        let mut blocks = HashMap::new();
+        let mut missing = HashSet::new();
+        {
+            let mut cache = self.cache.lock().unwrap();
+            for hash in hashes {
+                if let Some(block) = cache.get(&hash) {
+                    blocks.insert(hash, block.clone());
+                } else {
+                    missing.insert(hash);
+                }
             }
+        }
+        let missing: Vec<String> = missing.into_iter().collect();
+
+        if !missing.is_empty() {
+            let replicas = self.replicas.clone();
+
+            let handle: JoinHandle<Result<HashMap<String, DataCapsuleBlock>, Box<dyn Error + Send + Sync>>> = self.runtime.spawn(async move {
+                // a lagging replica may answer only part of the batch, the rest is asked from the others
+                let blocks = replicas.call_partial(missing, |mut client, hashes| async move {
+                    let mut blocks = HashMap::new();
+                    for chunk in hashes.chunks(BATCH_GET_LIMIT) {
+                        // the server cuts a response short once it gets large, the rest is asked again
+                        let mut pending = chunk.to_vec();
+                        while !pending.is_empty() {
+                            let response = client.batch_get(BatchGetRequest { block_hashes: pending.clone() }).await?.into_inner().blocks;
+                            if response.is_empty() {
+                                break; // none of them is known
+                            }
+                            pending.retain(|x| !response.contains_key(x));
+                            blocks.extend(response);
+                        }
+                    }
+                    Ok(blocks)
+                }).await?;
+                Ok(blocks)
+            });
 
+            for (hash, block) in handle.await?? {
+                let block = self.accept(&hash, block)?;
+                blocks.insert(hash, block);
+            }
         }
+        Ok(blocks)
+    }
+
+    // blocks are only cached after checking that they are what was asked for
+    fn accept(&self, hash: &str, mut block: DataCapsuleBlock) -> Result<DataCapsuleBlock, Box<dyn Error + Send + Sync>> {
+        if block.hash() != hash {
+            return Err(Box::new(ClientError::new(format!("Server returned a different block for {}", hash))));
+        }
+        if self.enable_crypto {
+            if !block.validate(&self.verifying_key) {
+                return Err(Box::new(ClientError::new(format!("Invalid server signature on block {}", hash))));
+            }
+            if !block.fs.as_mut().is_some_and(validate_file_system_block) {
+                return Err(Box::new(ClientError::new(format!("Invalid author signature on block {}", hash))));
+            }
+        }
+        self.cache.lock().unwrap().put(hash.to_string(), block.clone());
+        Ok(block)
     }
 
     pub fn get_leafs(&mut self) -> Result<Vec<String>, Box<dyn Error>> {
         return self.runtime.block_on(async {
-            let request = tonic::Request::new(LeafsRequest {});
-            let response = self.client.leafs(request).await?;
-            Ok(response.get_ref().clone().leaf_ids)
+            let response = self.replicas.call(|mut client| async move {
+                client.leafs(LeafsRequest {}).await
+            }).await?;
+            Ok(response.into_inner().leaf_ids)
+        });
+    }
+
+    pub fn get_stats(&self) -> Result<StatsResponse, Box<dyn Error>> {
+        return self.runtime.block_on(async {
+            let response = self.replicas.call(|mut client| async move {
+                client.stats(StatsRequest {}).await
+            }).await?;
+            Ok(response.into_inner())
+        });
+    }
+}
+
+impl INodeClient {
+    pub fn is_crypto_enabled(&self) -> bool {
+        self.enable_crypto
+    }
+
+    // sends the leafs that are new since the last poll, or since `leafs` at first, to `sender`, every `interval`, in the background
+    pub fn poll_leafs(&self, sender: Sender<(String, Option<DataCapsuleBlock>)>, interval: Duration, leafs: Vec<String>) {
+        let replicas = self.replicas.clone();
+
+        self.runtime.spawn(async move {
+            let mut known: HashSet<String> = leafs.into_iter().collect();
+            loop {
+                tokio::time::sleep(interval).await;
+                let leafs = replicas.call(|mut client| async move {
+                    client.leafs(LeafsRequest {}).await
+                }).await;
+                let leafs: HashSet<String> = match leafs {
+                    Ok(response) => response.into_inner().leaf_ids.into_iter().collect(),
+                    Err(e) => {
+                        warn!("Unable to poll leafs: {}", e);
+                        continue;
+                    }
+                };
+                for leaf in leafs.difference(&known) {
+                    if sender.send((leaf.clone(), None)).is_err() {
+                        return; // cache is gone
+                    }
+                }
+                known = leafs;
+            }
+        });
+    }
+
+    /* Sends every block accepted by the server from now on to `sender`, in the background.
+     * The leafs are sent again (without their blocks) whenever the subscription is (re-)established,
+     * so nothing accepted while disconnected is missed.
+     */
+    pub fn subscribe(&self, sender: Sender<(String, Option<DataCapsuleBlock>)>) {
+        let replicas = self.replicas.clone();
+        let verifying_key = self.verifying_key.clone();
+        let enable_crypto = self.enable_crypto;
+
+        self.runtime.spawn(async move {
+            loop {
+                let subscription = replicas.call(|mut client| async move {
+                    client.subscribe(SubscribeRequest { include_block: true }).await
+                }).await;
+                match subscription {
+                    Ok(response) => {
+                        let mut stream = response.into_inner();
+                        let leafs = replicas.call(|mut client| async move {
+                            client.leafs(LeafsRequest {}).await
+                        }).await;
+                        if let Ok(response) = leafs {
+                            for leaf in response.into_inner().leaf_ids {
+                                if sender.send((leaf, None)).is_err() {
+                                    return;
+                                }
+                            }
+                        }
+
+                        loop {
+                            match stream.message().await {
+                                Ok(Some(message)) => {
+                                    let mut block = message.block;
+                                    if let Some(block) = &mut block {
+                                        if block.hash() != message.hash {
+                                            warn!("Dropping block {} with a mismatching hash", message.hash);
+                                            continue;
+                                        }
+                                        if enable_crypto && !(block.validate(&verifying_key) && block.fs.as_mut().is_some_and(validate_file_system_block)) {
+                                            warn!("Dropping block {} with an invalid signature", message.hash);
+                                            continue;
+                                        }
+                                    }
+                                    if sender.send((message.hash, block)).is_err() {
+                                        return; // cache is gone
+                                    }
+                                }
+                                Ok(None) => break,
+                                Err(e) => {
+                                    warn!("Subscription ended: {}", e);
+                                    break;
+                                }
+                            }
+                        }
+                    }
+                    Err(e) => warn!("Unable to subscribe: {}", e),
+                }
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
         });
     }
 }
 
 impl BlockClient {
-    pub async fn get_block(&self, hash: String) -> Result<Vec<u8>, Box<dyn Error>> {
-        let response = self.get(hash).await;
-        if let Block::Data(data) = response.unwrap().fs.unwrap().block.unwrap() {
+    // data blocks are always chained directly to the data root
+    pub async fn get_block(&self, hash: String, root: &str) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+        let block = self.get(hash.clone()).await?;
+        BlockClient::get_data(&hash, block, root)
+    }
+
+    // unlike get_many, fails if a block is unknown
+    pub async fn get_blocks(&self, hashes: Vec<String>, root: &str) -> Result<HashMap<String, Vec<u8>>, Box<dyn Error + Send + Sync>> {
+        let mut result = HashMap::new();
+        for (hash, block) in self.get_many(hashes.clone()).await? {
+            let data = BlockClient::get_data(&hash, block, root)?;
+            result.insert(hash, data);
+        }
+        if let Some(hash) = hashes.iter().find(|x| !result.contains_key(*x)) {
+            return Err(Box::new(ClientError::new(format!("Unknown block {}", hash))));
+        }
+        Ok(result)
+    }
+
+    fn get_data(hash: &str, block: DataCapsuleBlock, root: &str) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+        if block.prev_hash != root {
+            return Err(Box::new(ClientError::new(format!("Block {} is not chained to the data root", hash))));
+        }
+        if let Some(Block::Data(data)) = block.fs.and_then(|fs| fs.block) {
             Ok(data.data)
         } else {
-            Err(Box::new(ClientError {}))
+            Err(Box::new(ClientError::new(format!("Block {} is not a data block", hash))))
         }
     }
 }
@@ -171,8 +482,11 @@ impl FSMiddlewareClient {
                 block: Some(block)
             });
             let handle: JoinHandle<Result<PutINodeResponse, Box<dyn Error + Send + Sync>>> = self.runtime.spawn(async move {
-                let response = client.put_i_node(request).await?;
-                Ok(response.get_ref().clone())
+                let response = client.put_i_node(request).await?.into_inner();
+                if !response.success || response.hash.is_none() {
+                    return Err(Box::new(ClientError::new("Middleware did not accept the inode block")) as Box<dyn Error + Send + Sync>);
+                }
+                Ok(response)
             });
 
             Ok(handle.await??)
@@ -193,8 +507,11 @@ impl FSMiddlewareClient {
                 inode_hash: ref_inode_hash,
             });
             let handle: JoinHandle<Result<PutDataResponse, Box<dyn Error + Send + Sync>>> = self.runtime.spawn(async move {
-                let response = client.put_data(request).await?;
-                Ok(response.get_ref().clone())
+                let response = client.put_data(request).await?.into_inner();
+                if !response.success || response.hash.is_none() {
+                    return Err(Box::new(ClientError::new("Middleware did not accept the data block")) as Box<dyn Error + Send + Sync>);
+                }
+                Ok(response)
             });
             Ok(handle.await??)
         } else {
@@ -216,4 +533,52 @@ impl FSMiddlewareClient {
 
         return id;
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+
+    use crate::proto::block::DataBlock;
+    use crate::test_util::verifying_key;
+    use super::*;
+
+    // without replicas, only cached blocks can be read
+    fn test_client() -> BlockClient {
+        BlockClient::connect(&[], ClientTlsConfig::new(), 8, verifying_key(), false)
+    }
+
+    fn data_block(prev_hash: &str, data: &[u8]) -> DataCapsuleBlock {
+        DataCapsuleBlock {
+            prev_hash: prev_hash.into(),
+            fs: Some(DataCapsuleFileSystemBlock {
+                prev_hash: prev_hash.into(),
+                block: Some(Block::Data(DataBlock { data: data.to_vec() })),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn accept_checks_the_hash() {
+        let client = test_client();
+        let block = data_block("root", b"data");
+        let other = data_block("root", b"other");
+        assert!(client.accept(&other.hash(), block.clone()).is_err());
+        assert!(!client.cache.lock().unwrap().contains(&other.hash()));
+
+        assert_eq!(client.accept(&block.hash(), block.clone()).unwrap(), block);
+        assert_eq!(block_on(client.get(block.hash())).unwrap(), block); // from the cache
+    }
+
+    #[test]
+    fn data_is_chained_to_the_root() {
+        let block = data_block("root", b"data");
+        assert_eq!(BlockClient::get_data("a", block.clone(), "root").unwrap(), b"data");
+        assert!(BlockClient::get_data("a", block, "other").is_err());
+
+        let mut inode = data_block("root", b"");
+        inode.fs.as_mut().unwrap().block = Some(Block::Inode(Default::default()));
+        assert!(BlockClient::get_data("a", inode, "root").is_err());
+    }
+}
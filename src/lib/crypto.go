@@ -10,7 +10,6 @@ import (
 	"encoding/base64"
 	"encoding/pem"
 	"fmt"
-	"github.com/golang/protobuf/proto"
 	"os"
 	"time"
 )
@@ -18,15 +17,11 @@ import (
 func SignDataCapsuleBlock(block *go_proto.DataCapsuleBlock, privateKey *rsa.PrivateKey) {
 	block.Signature = []byte{}
 	block.Timestamp = time.Now().UnixNano()
-	block.Signature = SignData(block, privateKey)
+	block.Signature = SignData(CanonicalDataCapsuleBlock(block), privateKey)
 }
 
-func SignData(data proto.Message, privateKey *rsa.PrivateKey) []byte {
-	bytes, err := proto.Marshal(data)
-	if err != nil {
-		panic(err)
-	}
-	hashed := sha256.Sum256(bytes)
+func SignData(data []byte, privateKey *rsa.PrivateKey) []byte {
+	hashed := sha256.Sum256(data)
 	signature, err := rsa.SignPKCS1v15(nil, privateKey, crypto.SHA256, hashed[:])
 	if err != nil {
 		panic(err)
@@ -36,13 +31,13 @@ func SignData(data proto.Message, privateKey *rsa.PrivateKey) []byte {
 }
 
 func ValidateDataCapsuleFileSystemBlock(block *go_proto.DataCapsuleFileSystemBlock) bool {
-	if !ValidateID(block.UpdatedBy) {
+	if block.UpdatedBy == nil || !ValidateID(block.UpdatedBy) {
 		return false
 	}
 	signature := block.Signature
 	block.Signature = []byte{}
 
-	result := ValidateData(block, LoadPublicKey(block.UpdatedBy.PubKey), signature)
+	result := ValidateData(CanonicalFileSystemBlock(block), LoadPublicKey(block.UpdatedBy.PubKey), signature)
 	block.Signature = signature
 
 	return result
@@ -52,23 +47,15 @@ func ValidateID(id *go_proto.ID) bool {
 	signature := id.Signature
 	id.Signature = []byte{}
 
-	result := ValidateData(id, LoadPublicKey(id.PubKey), signature)
+	result := ValidateData(CanonicalID(id), LoadPublicKey(id.PubKey), signature)
 	id.Signature = signature
 
 	return result
 }
 
-func ValidateData(data proto.Message, publicKey *rsa.PublicKey, signature []byte) bool {
-	bytes, err := proto.Marshal(data)
-	if err != nil {
-		panic(err)
-	}
-
-	hashed := sha256.Sum256(bytes)
-	result := rsa.VerifyPKCS1v15(publicKey, crypto.SHA256, hashed[:], signature)
-
-	return result == nil ||
-		result != nil // todo: remove this line by figuring out a way to produce unique serialization
+func ValidateData(data []byte, publicKey *rsa.PublicKey, signature []byte) bool {
+	hashed := sha256.Sum256(data)
+	return rsa.VerifyPKCS1v15(publicKey, crypto.SHA256, hashed[:], signature) == nil
 }
 
 func LoadPublicKey(key []byte) *rsa.PublicKey {
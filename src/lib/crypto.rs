@@ -3,9 +3,11 @@ use duplicate::duplicate_item;
 use prost::Message;
 use ring::digest::{Context, SHA256};
 use rsa::pkcs1v15::{SigningKey, VerifyingKey, Signature};
+use rsa::pkcs8::DecodePublicKey;
 use rsa::sha2::Sha256;
 use rsa::signature::{SignatureEncoding, Signer, Verifier};
-use crate::proto::block::{DataCapsuleBlock, DataCapsuleFileSystemBlock, Id};
+use crate::proto::block::{DataBlock, DataCapsuleBlock, DataCapsuleFileSystemBlock, Id, INodeBlock};
+use crate::proto::block::data_capsule_file_system_block::Block;
 
 pub trait SignableBlock {
     fn sign(&mut self, key: &SigningKey<Sha256>);
@@ -26,8 +28,7 @@ impl SignableBlock for T {
         let result = validate_signature(self, key, &signature);
         self.signature = signature;
 
-        // return result; Disabled due to differences in Protobuf Serialization
-        return true;
+        return result;
     }
 
     fn hash(&self) -> String {
@@ -39,18 +40,271 @@ impl SignableBlock for T {
     }
 }
 
-fn sign_data<T>(data: &T, key: &SigningKey<Sha256>) -> Vec<u8> where T: Message {
-    let mut context = Context::new(&SHA256);
+/* Checks that the author in updatedBy signed both its ID and the block.
+ */
+pub fn validate_file_system_block(block: &mut DataCapsuleFileSystemBlock) -> bool {
+    let Some(key) = block.updated_by.as_mut().and_then(validate_id) else {
+        return false;
+    };
+    return block.validate(&key);
+}
+
+/* Checks that the author of an inode block is in the write allow list of the inode block it is
+ * attached to, as the middleware does before signing it. Data blocks are attached to other data
+ * blocks, which have no list, so they are only covered by the signature of the middleware.
+ */
+pub fn is_allowed_writer(block: &DataCapsuleFileSystemBlock, parent: &DataCapsuleFileSystemBlock) -> bool {
+    let Some(Block::Inode(_)) = &block.block else {
+        return true;
+    };
+    let (Some(author), Some(Block::Inode(parent))) = (&block.updated_by, &parent.block) else {
+        return false;
+    };
+    return parent.write_allow_list.iter()
+        .any(|id| id.pub_key == author.pub_key && id.uid == author.uid && validate_id(&mut id.clone()).is_some());
+}
+
+// the key of an ID that is signed by itself
+fn validate_id(id: &mut Id) -> Option<VerifyingKey<Sha256>> {
+    let key = std::str::from_utf8(&id.pub_key).ok()
+        .and_then(|pem| VerifyingKey::<Sha256>::from_public_key_pem(pem).ok())?;
+    return if id.validate(&key) { Some(key) } else { None };
+}
+
+fn sign_data<T>(data: &T, key: &SigningKey<Sha256>) -> Vec<u8> where T: Canonical {
     let mut buf = vec![];
-    data.encode(&mut buf).unwrap();
-    context.update(&buf);
+    data.canonical(&mut buf);
     return key.sign(&buf).to_vec();
 }
 
-fn validate_signature<T>(data: &T, key: &VerifyingKey<Sha256>, signature: &Vec<u8>) -> bool where T: Message {
-    let mut context = Context::new(&SHA256);
+fn validate_signature<T>(data: &T, key: &VerifyingKey<Sha256>, signature: &Vec<u8>) -> bool where T: Canonical {
     let mut buf = vec![];
-    data.encode(&mut buf).unwrap();
-    context.update(&buf);
-    return key.verify(&buf, &Signature::try_from(signature.as_slice()).unwrap()).is_ok();
-}
\ No newline at end of file
+    data.canonical(&mut buf);
+    return match Signature::try_from(signature.as_slice()) {
+        Ok(signature) => key.verify(&buf, &signature).is_ok(),
+        Err(_) => false,
+    };
+}
+
+/* Deterministic encoding that is signed instead of the protobuf serialization, which differs
+ * between prost and Go. Must be kept in sync with src/lib/canonical.go.
+ *
+ * Fields are written in declaration order:
+ * - bytes and strings: length as u64 big-endian, followed by the content
+ * - integers and enums: 8 bytes big-endian
+ * - optional integers: 1 byte presence flag (0 or 1), followed by the value if present
+ * - messages: 1 byte presence flag (0 or 1), followed by the fields if present
+ * - repeated fields: count as u64 big-endian, followed by the items
+ * - maps: count as u64 big-endian, followed by key and value of each entry in ascending key order
+ * - oneof: 1 byte with the index of the set case (0 if none), followed by its value
+ */
+pub trait Canonical {
+    fn canonical(&self, buf: &mut Vec<u8>);
+}
+
+fn canonical_bytes(buf: &mut Vec<u8>, data: &[u8]) {
+    buf.extend_from_slice(&(data.len() as u64).to_be_bytes());
+    buf.extend_from_slice(data);
+}
+
+fn canonical_u64(buf: &mut Vec<u8>, value: u64) {
+    buf.extend_from_slice(&value.to_be_bytes());
+}
+
+fn canonical_optional_u64(buf: &mut Vec<u8>, value: Option<u64>) {
+    if let Some(value) = value {
+        buf.push(1);
+        canonical_u64(buf, value);
+    } else {
+        buf.push(0);
+    }
+}
+
+fn canonical_message<T: Canonical>(buf: &mut Vec<u8>, message: &Option<T>) {
+    if let Some(message) = message {
+        buf.push(1);
+        message.canonical(buf);
+    } else {
+        buf.push(0);
+    }
+}
+
+impl Canonical for Id {
+    fn canonical(&self, buf: &mut Vec<u8>) {
+        canonical_bytes(buf, &self.pub_key);
+        canonical_u64(buf, self.uid);
+        canonical_bytes(buf, &self.signature);
+    }
+}
+
+impl Canonical for DataBlock {
+    fn canonical(&self, buf: &mut Vec<u8>) {
+        canonical_bytes(buf, &self.data);
+    }
+}
+
+impl Canonical for INodeBlock {
+    fn canonical(&self, buf: &mut Vec<u8>) {
+        canonical_bytes(buf, &self.filename);
+        canonical_u64(buf, self.size);
+        canonical_u64(buf, self.kind as u64);
+        canonical_bytes(buf, self.moved_from.as_bytes());
+        canonical_bytes(buf, &self.target);
+        canonical_optional_u64(buf, self.mode.map(|x| x as u64));
+        canonical_optional_u64(buf, self.uid.map(|x| x as u64));
+        canonical_optional_u64(buf, self.gid.map(|x| x as u64));
+        canonical_optional_u64(buf, self.mtime.map(|x| x as u64));
+        canonical_bytes(buf, self.link_id.as_bytes());
+        canonical_u64(buf, self.xattrs.len() as u64);
+        for (name, value) in &self.xattrs { // BTreeMap, already sorted
+            canonical_bytes(buf, name.as_bytes());
+            canonical_bytes(buf, value);
+        }
+        canonical_bytes(buf, self.base.as_bytes());
+        canonical_u64(buf, self.hashes.len() as u64);
+        for hash in &self.hashes {
+            canonical_bytes(buf, hash.as_bytes());
+        }
+        canonical_u64(buf, self.write_allow_list.len() as u64);
+        for id in &self.write_allow_list {
+            id.canonical(buf);
+        }
+    }
+}
+
+impl Canonical for DataCapsuleFileSystemBlock {
+    fn canonical(&self, buf: &mut Vec<u8>) {
+        canonical_bytes(buf, self.prev_hash.as_bytes());
+        match &self.block {
+            None => buf.push(0),
+            Some(Block::Data(data)) => {
+                buf.push(1);
+                data.canonical(buf);
+            }
+            Some(Block::Inode(inode)) => {
+                buf.push(2);
+                inode.canonical(buf);
+            }
+        }
+        canonical_message(buf, &self.updated_by);
+        canonical_bytes(buf, &self.signature);
+    }
+}
+
+impl Canonical for DataCapsuleBlock {
+    fn canonical(&self, buf: &mut Vec<u8>) {
+        canonical_bytes(buf, self.prev_hash.as_bytes());
+        canonical_message(buf, &self.fs);
+        canonical_u64(buf, self.timestamp as u64);
+        canonical_bytes(buf, &self.signature);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use rsa::RsaPrivateKey;
+    use rsa::pkcs8::{EncodePublicKey, LineEnding};
+    use rsa::rand_core::OsRng;
+
+    use crate::proto::block::i_node_block::Kind;
+    use super::*;
+
+    // the block encoded in testdata/canonical.hex, also built by TestCanonicalDataCapsuleBlock in canonical_test.go
+    fn sample_block() -> DataCapsuleBlock {
+        let id = Id { pub_key: b"key".to_vec(), uid: 1000, signature: vec![1, 2] };
+        let inode = INodeBlock {
+            filename: b"a.txt".to_vec(),
+            size: 5,
+            kind: Kind::Symlink.into(),
+            moved_from: "m".into(),
+            target: b"t".to_vec(),
+            mode: Some(0o644),
+            uid: Some(1000),
+            gid: None,
+            mtime: Some(-1),
+            link_id: "l".into(),
+            xattrs: BTreeMap::from([("user.b".into(), vec![2]), ("user.a".into(), vec![1])]),
+            base: "b".into(),
+            hashes: vec!["h1".into(), "".into()],
+            write_allow_list: vec![id.clone()],
+        };
+        DataCapsuleBlock {
+            prev_hash: "p".into(),
+            fs: Some(DataCapsuleFileSystemBlock {
+                prev_hash: "p".into(),
+                block: Some(Block::Inode(inode)),
+                updated_by: Some(id),
+                signature: vec![3],
+            }),
+            timestamp: 42,
+            signature: vec![],
+        }
+    }
+
+    #[test]
+    fn canonical_matches_go() {
+        let mut buf = vec![];
+        sample_block().canonical(&mut buf);
+        assert_eq!(HEXLOWER.encode(&buf), include_str!("testdata/canonical.hex").trim());
+    }
+
+    #[test]
+    fn canonical_absent_fields() {
+        let mut buf = vec![];
+        DataCapsuleFileSystemBlock::default().canonical(&mut buf);
+        // empty prev hash, no block, no author, empty signature
+        assert_eq!(buf, [vec![0u8; 8], vec![0, 0], vec![0u8; 8]].concat());
+    }
+
+    fn signed_id(key: &RsaPrivateKey, uid: u64) -> Id {
+        let pem = key.to_public_key().to_public_key_pem(LineEnding::LF).unwrap();
+        let mut id = Id { pub_key: pem.into_bytes(), uid, signature: vec![] };
+        id.sign(&SigningKey::<Sha256>::new(key.clone()));
+        id
+    }
+
+    fn inode_block(author: Option<Id>, write_allow_list: Vec<Id>) -> DataCapsuleFileSystemBlock {
+        DataCapsuleFileSystemBlock {
+            block: Some(Block::Inode(INodeBlock { write_allow_list, ..Default::default() })),
+            updated_by: author,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn allowed_writer() {
+        let key = RsaPrivateKey::new(&mut OsRng, 1024).unwrap();
+        let other = RsaPrivateKey::new(&mut OsRng, 1024).unwrap();
+        let parent = inode_block(None, vec![signed_id(&key, 1000)]);
+
+        assert!(is_allowed_writer(&inode_block(Some(signed_id(&key, 1000)), vec![]), &parent));
+        assert!(!is_allowed_writer(&inode_block(Some(signed_id(&key, 1001)), vec![]), &parent));
+        assert!(!is_allowed_writer(&inode_block(Some(signed_id(&other, 1000)), vec![]), &parent));
+        assert!(!is_allowed_writer(&inode_block(None, vec![]), &parent));
+
+        let data = DataCapsuleFileSystemBlock { block: Some(Block::Data(DataBlock { data: vec![1] })), ..Default::default() };
+        assert!(is_allowed_writer(&data, &parent));
+    }
+
+    #[test]
+    fn allowed_writer_needs_signed_id() {
+        let key = RsaPrivateKey::new(&mut OsRng, 1024).unwrap();
+        let mut unsigned = signed_id(&key, 1000);
+        unsigned.signature = vec![];
+        let parent = inode_block(None, vec![unsigned.clone()]);
+        assert!(!is_allowed_writer(&inode_block(Some(unsigned), vec![]), &parent));
+    }
+
+    #[test]
+    fn signature_roundtrip() {
+        let key = RsaPrivateKey::new(&mut OsRng, 1024).unwrap();
+        let mut block = sample_block().fs.unwrap();
+        block.sign(&SigningKey::<Sha256>::new(key.clone()));
+        assert!(block.validate(&VerifyingKey::<Sha256>::new(key.to_public_key())));
+
+        block.prev_hash = "q".into();
+        assert!(!block.validate(&VerifyingKey::<Sha256>::new(key.to_public_key())));
+    }
+}
@@ -1,31 +1,271 @@
 use std::cmp::max;
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
 use std::ffi::OsStr;
 use std::os::unix::ffi::OsStrExt;
-use std::time::{Duration, SystemTime};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{Receiver, Sender};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use fuser::{Filesystem, FileType, ReplyAttr, ReplyCreate, ReplyData, ReplyDirectory, ReplyEmpty, ReplyEntry, ReplyWrite, Request, TimeOrNow};
-use fuser::FileType::{Directory, RegularFile};
-use libc::ENOENT;
-use log::debug;
+use fuser::{FileAttr, Filesystem, FileType, Notifier, ReplyAttr, ReplyCreate, ReplyData, ReplyDirectory, ReplyEmpty, ReplyEntry, ReplyOpen, ReplyStatfs, ReplyWrite, ReplyXattr, Request, TimeOrNow};
+use fuser::FileType::{Directory, RegularFile, Symlink};
+use libc::{EACCES, EBADF, EEXIST, EINVAL, EIO, EISDIR, ENODATA, ENOENT, ENOTDIR, ENOTEMPTY, ENOTSUP, EPERM, ERANGE, EROFS, O_ACCMODE, O_RDONLY, RENAME_EXCHANGE, RENAME_NOREPLACE, XATTR_CREATE, XATTR_REPLACE};
+use log::{debug, warn};
 
-use crate::cache::Cache;
+use crate::cache::{Cache, Change};
+use crate::history;
+use crate::history::HISTORY_NAME;
+use crate::inode::INode;
 use crate::proto::block::i_node_block::Kind;
 
 const TTL: Duration = Duration::from_secs(1); // 1 second
+const XATTR_USER_PREFIX: &str = "user.";
+pub const XATTR_RESTORE: &str = "user.cfs.restore"; // set on a directory to undelete the entry named by the value
+const UNLIMITED: u64 = u64::MAX / 2; // reported by statfs for capsules without a quota
+const REFRESH_PERIOD: Duration = Duration::from_secs(1); // between applying the blocks of other clients
+
+/* Limits for the writes buffered by an open file before they are published. The age is checked
+ * on the next write or getattr.
+ */
+pub struct WriteBack {
+    pub max_bytes: usize,
+    pub max_age: Duration,
+}
+
+impl Default for WriteBack {
+    fn default() -> Self {
+        WriteBack {
+            max_bytes: 4 * 1024 * 1024,
+            max_age: Duration::from_secs(5),
+        }
+    }
+}
+
+/* An open file. Reads see the version of the inode at open, so they are not affected by other
+ * writers committing, unless the inode has unpublished writes of this client.
+ */
+struct FileHandle {
+    ino: u64,
+    writable: bool,
+    inode: INode,
+}
+
+/* Writes to an inode that are not published yet. They are buffered in the journal of the inode,
+ * which is shared by all handles of the inode, until it is flushed.
+ */
+struct Dirty {
+    inode: INode,
+    uid: u32, // who wrote the buffered blocks
+    since: Instant, // first unpublished write
+}
 
 pub struct CFS {
     pub cache: Cache,
+    write_back: WriteBack,
+    handles: HashMap<u64, FileHandle>,
+    dirty: HashMap<u64, Dirty>, // by ino
+    next_fh: u64,
+    notifications: Option<Sender<Vec<Change>>>, // to notify_kernel
 }
 
 impl CFS {
+    pub fn new(cache: Cache, write_back: WriteBack, notifications: Option<Sender<Vec<Change>>>) -> CFS {
+        CFS {
+            cache,
+            write_back,
+            handles: HashMap::new(),
+            dirty: HashMap::new(),
+            next_fh: 1,
+            notifications,
+        }
+    }
+
+    fn refresh(&mut self) {
+        let changes = self.cache.refresh();
+        if let Some(notifications) = &self.notifications {
+            if !changes.is_empty() && notifications.send(changes).is_err() {
+                warn!("Unable to notify the kernel of changed entries");
+            }
+        }
+    }
+
+    fn attr(&self, ino: u64) -> FileAttr {
+        let mut attr = self.get_inode(ino).to_file_attr();
+        attr.nlink = self.cache.nlink(ino);
+        return attr;
+    }
+
+    fn open_handle(&mut self, ino: u64, writable: bool) -> u64 {
+        let inode = self.cache.get_inode(ino).0;
+        return self.insert_handle(ino, writable, inode);
+    }
+
+    fn insert_handle(&mut self, ino: u64, writable: bool, inode: INode) -> u64 {
+        let fh = self.next_fh;
+        self.next_fh += 1;
+        self.handles.insert(fh, FileHandle {
+            ino,
+            writable,
+            inode,
+        });
+        return fh;
+    }
+
+    // the error for changing an entry of the read-only history tree, if name is one
+    fn read_only(&self, parent: u64, name: &OsStr) -> Option<i32> {
+        if history::is_reserved(parent, name) {
+            Some(EEXIST)
+        } else if history::is_virtual(parent) {
+            Some(EROFS)
+        } else {
+            None
+        }
+    }
+
+    // like get_inode, but also for versions in the history tree
+    fn view(&self, ino: u64) -> Option<INode> {
+        if history::is_virtual(ino) {
+            history::version(&self.cache, ino)
+        } else {
+            Some(self.get_inode(ino))
+        }
+    }
+
+    // the buffered version if there are unpublished writes
+    fn get_inode(&self, ino: u64) -> INode {
+        return match self.dirty.get(&ino) {
+            Some(dirty) => dirty.inode.clone(),
+            None => self.cache.get_inode(ino).0,
+        };
+    }
+
+    // publishes the buffered blocks and then a single new version of the inode
+    fn flush_ino(&mut self, ino: u64) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let Some(dirty) = self.dirty.get_mut(&ino) else {
+            return Ok(());
+        };
+        if self.cache.get_inode(ino).0.is_deleted() {
+            // unlinked while open, publishing would bring it back. The handles keep reading the
+            // buffered writes until the last one is released.
+            return Ok(());
+        }
+        dirty.inode.flush(dirty.uid)?;
+        self.cache.try_update(dirty.uid, ino, Some(&dirty.inode), dirty.inode.block.clone())?;
+        self.dirty.remove(&ino);
+        let inode = self.cache.get_inode(ino).0;
+        for handle in self.handles.values_mut().filter(|x| x.ino == ino && x.writable) {
+            handle.inode = inode.clone(); // writers read what they wrote
+        }
+        Ok(())
+    }
+
+    fn flush_expired(&mut self) {
+        let expired: Vec<u64> = self.dirty.iter()
+            .filter(|(_, x)| x.since.elapsed() >= self.write_back.max_age)
+            .map(|(ino, _)| *ino)
+            .collect();
+        for ino in expired {
+            if let Err(e) = self.flush_ino(ino) {
+                warn!("Unable to flush inode {}: {}", ino, e);
+            }
+        }
+    }
+
+    fn reply_flush(&mut self, ino: u64, reply: ReplyEmpty) {
+        match self.flush_ino(ino) {
+            Ok(()) => reply.ok(),
+            Err(e) => {
+                warn!("Unable to flush inode {}: {}", ino, e);
+                reply.error(EIO);
+            }
+        }
+    }
+
+    // publishes a change of the xattrs of ino
+    fn update_xattrs<F>(&mut self, req: &Request<'_>, ino: u64, reply: ReplyEmpty, f: F) where F: FnOnce(&mut BTreeMap<String, Vec<u8>>) -> Result<(), i32> {
+        if ino == 1 {
+            reply.error(EPERM); // the root has no later versions
+            return;
+        }
+        if self.flush_ino(ino).is_err() {
+            reply.error(EIO);
+            return;
+        }
+        let mut block = self.cache.get_inode(ino).0.block;
+        if let Err(e) = f(&mut block.xattrs) {
+            reply.error(e);
+            return;
+        }
+        if let Err(e) = self.cache.update(req.uid(), ino, block) {
+            warn!("Unable to update inode {}: {}", ino, e);
+            reply.error(EIO);
+            return;
+        }
+        reply.ok();
+    }
+
+    fn restore(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        self.refresh();
+        if self.cache.get_inode(parent).0.get_file_type() != Directory {
+            reply.error(ENOTDIR);
+            return;
+        }
+        if self.read_only(parent, name).is_some() || self.cache.find_child_node(parent, name).is_some() {
+            reply.error(EEXIST);
+            return;
+        }
+        if !self.may_add_entries(req, parent) {
+            reply.error(EACCES);
+            return;
+        }
+        match self.cache.restore(req.uid(), parent, name) {
+            Ok(true) => reply.ok(),
+            Ok(false) => reply.error(ENOENT),
+            Err(e) => {
+                warn!("Unable to restore {:?}: {}", name, e);
+                reply.error(EIO);
+            }
+        }
+    }
+
+    /* Whether the user may create entries in the directory. Checked explicitly for restores, as the
+     * kernel only knows them as setting an xattr.
+     */
+    fn may_add_entries(&self, req: &Request<'_>, ino: u64) -> bool {
+        let attr = self.cache.get_inode(ino).0.to_file_attr();
+        let perm = attr.perm as u32;
+        let bits = if req.uid() == 0 {
+            return true;
+        } else if req.uid() == attr.uid {
+            perm >> 6
+        } else if req.gid() == attr.gid {
+            perm >> 3
+        } else {
+            perm
+        };
+        return bits & 0o3 == 0o3; // write and search
+    }
+
     fn _delete(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, file_type: FileType, reply: ReplyEmpty) {
+        if self.read_only(parent, name).is_some() {
+            reply.error(EROFS);
+            return;
+        }
         let node = self.cache.find_child_node(parent, name);
         if let Some(node) = node {
-            if node.get_file_type() != file_type {
+            // unlink removes files and symlinks, rmdir only directories
+            if (node.get_file_type() == Directory) != (file_type == Directory) {
                 reply.error(ENOENT);
             } else {
-                self.cache.delete(req.uid(), node.ino);
-                reply.ok();
+                match self.cache.delete(req.uid(), &node) {
+                    Ok(()) => reply.ok(),
+                    Err(e) => {
+                        warn!("Unable to delete {:?}: {}", name, e);
+                        reply.error(EIO);
+                    }
+                }
             }
         } else {
             reply.error(ENOENT);
@@ -34,36 +274,280 @@ impl CFS {
 }
 
 
+/* Invalidates the kernel's cache of the entries other clients changed. This runs on its own thread,
+ * as the kernel may wait for the request that is being handled before applying a notification.
+ */
+pub fn notify_kernel(notifier: Notifier, changes: Receiver<Vec<Change>>) {
+    thread::spawn(move || {
+        for change in changes.into_iter().flatten() {
+            // the kernel reports entries and inodes it has not cached as not found
+            if let Err(e) = notifier.inval_entry(change.parent, OsStr::from_bytes(&change.name)) {
+                debug!("Unable to invalidate entry {:?} of {}: {}", OsStr::from_bytes(&change.name), change.parent, e);
+            }
+            if let Err(e) = notifier.inval_inode(change.ino, 0, 0) {
+                debug!("Unable to invalidate inode {}: {}", change.ino, e);
+            }
+        }
+    });
+}
+
+/* The file system shared with a thread that applies the blocks published by other clients every
+ * REFRESH_PERIOD, so the kernel drops its stale entries even while the mount is idle. Requests
+ * take the lock for as long as they are handled.
+ */
+pub struct SharedCFS(Arc<Mutex<CFS>>);
+
+impl SharedCFS {
+    pub fn new(fs: CFS) -> SharedCFS {
+        let fs = Arc::new(Mutex::new(fs));
+        let weak = Arc::downgrade(&fs);
+        thread::spawn(move || loop {
+            thread::sleep(REFRESH_PERIOD);
+            match weak.upgrade() {
+                Some(fs) => fs.lock().unwrap().refresh(),
+                None => return, // unmounted
+            }
+        });
+        return SharedCFS(fs);
+    }
+}
+
+macro_rules! delegate {
+    ($($name:ident($($arg:ident: $ty:ty),*);)*) => {
+        $(fn $name(&mut self, $($arg: $ty),*) {
+            self.0.lock().unwrap().$name($($arg),*)
+        })*
+    };
+}
+
+impl Filesystem for SharedCFS {
+    delegate! {
+        destroy();
+        statfs(req: &Request<'_>, ino: u64, reply: ReplyStatfs);
+        lookup(req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry);
+        getattr(req: &Request<'_>, ino: u64, reply: ReplyAttr);
+        setattr(req: &Request<'_>, ino: u64, mode: Option<u32>, uid: Option<u32>, gid: Option<u32>, size: Option<u64>, atime: Option<TimeOrNow>, mtime: Option<TimeOrNow>, ctime: Option<SystemTime>, fh: Option<u64>, crtime: Option<SystemTime>, chgtime: Option<SystemTime>, bkuptime: Option<SystemTime>, flags: Option<u32>, reply: ReplyAttr);
+        mkdir(req: &Request<'_>, parent: u64, name: &OsStr, mode: u32, umask: u32, reply: ReplyEntry);
+        symlink(req: &Request<'_>, parent: u64, link_name: &OsStr, target: &Path, reply: ReplyEntry);
+        readlink(req: &Request<'_>, ino: u64, reply: ReplyData);
+        link(req: &Request<'_>, ino: u64, newparent: u64, newname: &OsStr, reply: ReplyEntry);
+        unlink(req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty);
+        rmdir(req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty);
+        rename(req: &Request<'_>, parent: u64, name: &OsStr, newparent: u64, newname: &OsStr, flags: u32, reply: ReplyEmpty);
+        open(req: &Request<'_>, ino: u64, flags: i32, reply: ReplyOpen);
+        read(req: &Request<'_>, ino: u64, fh: u64, offset: i64, size: u32, flags: i32, lock: Option<u64>, reply: ReplyData);
+        write(req: &Request<'_>, ino: u64, fh: u64, offset: i64, data: &[u8], write_flags: u32, flags: i32, lock_owner: Option<u64>, reply: ReplyWrite);
+        flush(req: &Request<'_>, ino: u64, fh: u64, lock_owner: u64, reply: ReplyEmpty);
+        release(req: &Request<'_>, ino: u64, fh: u64, flags: i32, lock_owner: Option<u64>, flush: bool, reply: ReplyEmpty);
+        fsync(req: &Request<'_>, ino: u64, fh: u64, datasync: bool, reply: ReplyEmpty);
+        setxattr(req: &Request<'_>, ino: u64, name: &OsStr, value: &[u8], flags: i32, position: u32, reply: ReplyEmpty);
+        getxattr(req: &Request<'_>, ino: u64, name: &OsStr, size: u32, reply: ReplyXattr);
+        listxattr(req: &Request<'_>, ino: u64, size: u32, reply: ReplyXattr);
+        removexattr(req: &Request<'_>, ino: u64, name: &OsStr, reply: ReplyEmpty);
+        readdir(req: &Request<'_>, ino: u64, fh: u64, offset: i64, reply: ReplyDirectory);
+        create(req: &Request<'_>, parent: u64, name: &OsStr, mode: u32, umask: u32, flags: i32, reply: ReplyCreate);
+    }
+}
+
 impl Filesystem for CFS {
+    fn destroy(&mut self) {
+        let dirty: Vec<u64> = self.dirty.keys().copied().collect();
+        for ino in dirty {
+            if let Err(e) = self.flush_ino(ino) {
+                warn!("Unable to flush inode {}: {}", ino, e);
+            }
+        }
+    }
+
+    // data capsule usage in blocks of the configured size, inode capsule usage as files
+    fn statfs(&mut self, _req: &Request<'_>, _ino: u64, reply: ReplyStatfs) {
+        let (inodes, data) = match self.cache.get_stats() {
+            Ok(stats) => stats,
+            Err(e) => {
+                warn!("Unable to get capsule stats: {}", e);
+                reply.error(EIO);
+                return;
+            }
+        };
+        let block_size = self.cache.block_size() as u64;
+        let used = data.total_bytes.div_ceil(block_size);
+        let blocks = max(data.max_bytes.map_or(UNLIMITED / block_size, |x| x / block_size), used);
+        let files = max(inodes.max_blocks.unwrap_or(UNLIMITED), inodes.block_count);
+        reply.statfs(blocks, blocks - used, blocks - used, files, files - inodes.block_count, block_size as u32, 255, block_size as u32);
+    }
+
     fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
-        if let Some(inode) = self.cache.find_child_node(parent, name) {
-            reply.entry(&TTL, &inode.to_file_attr(), 0);
+        self.refresh();
+        if history::is_reserved(parent, name) || history::is_virtual(parent) {
+            match history::lookup(&self.cache, parent, name).and_then(|x| history::attr(&self.cache, x)) {
+                Some(attr) => reply.entry(&TTL, &attr, 0),
+                None => reply.error(ENOENT),
+            }
+        } else if let Some(inode) = self.cache.find_child_node(parent, name) {
+            reply.entry(&TTL, &self.attr(inode.ino), 0);
         } else {
             reply.error(ENOENT);
         }
     }
 
     fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
-        if ino < self.cache.num_inodes() {
-            reply.attr(&TTL, &self.cache.get_inode(ino).0.to_file_attr());
+        self.flush_expired();
+        self.refresh();
+        if history::is_virtual(ino) {
+            match history::attr(&self.cache, ino) {
+                Some(attr) => reply.attr(&TTL, &attr),
+                None => reply.error(ENOENT),
+            }
+        } else if ino < self.cache.num_inodes() {
+            reply.attr(&TTL, &self.attr(ino));
         } else {
             reply.error(ENOENT)
         }
     }
 
-    fn setattr(&mut self, req: &Request<'_>, ino: u64, _mode: Option<u32>, _uid: Option<u32>, _gid: Option<u32>, size: Option<u64>, _atime: Option<TimeOrNow>, _mtime: Option<TimeOrNow>, _ctime: Option<SystemTime>, _fh: Option<u64>, _crtime: Option<SystemTime>, _chgtime: Option<SystemTime>, _bkuptime: Option<SystemTime>, _flags: Option<u32>, reply: ReplyAttr) {
+    fn setattr(&mut self, req: &Request<'_>, ino: u64, mode: Option<u32>, uid: Option<u32>, gid: Option<u32>, size: Option<u64>, _atime: Option<TimeOrNow>, mtime: Option<TimeOrNow>, _ctime: Option<SystemTime>, fh: Option<u64>, _crtime: Option<SystemTime>, _chgtime: Option<SystemTime>, _bkuptime: Option<SystemTime>, _flags: Option<u32>, reply: ReplyAttr) {
+        // ownership checks are done by the kernel, as we mount with DefaultPermissions
+        if history::is_virtual(ino) {
+            reply.error(EROFS);
+            return;
+        }
+        // the root has no later versions, it keeps the attributes it was generated with
+        if ino == 1 && (mode.is_some() || uid.is_some() || gid.is_some() || mtime.is_some()) {
+            reply.error(EPERM);
+            return;
+        }
+        if self.flush_ino(ino).is_err() {
+            reply.error(EIO);
+            return;
+        }
+        let mut inode = self.cache.get_inode(ino).0;
+        let mut changed = false;
         if let Some(size) = size {
-            let mut block = self.cache.get_inode(ino).0.block;
-            block.size = size;
-            self.cache.update(req.uid(), ino, block);
+            match inode.get_file_type() {
+                RegularFile => {}
+                Directory => {
+                    reply.error(EISDIR);
+                    return;
+                }
+                _ => {
+                    reply.error(EINVAL);
+                    return;
+                }
+            }
+            if let Err(e) = inode.truncate(req.uid(), size) {
+                warn!("Unable to truncate inode {}: {}", ino, e);
+                reply.error(EIO);
+                return;
+            }
+            inode.block.mtime = None; // the new version's timestamp is the modification time
+            changed = true;
+        }
+        let mut block = inode.block;
+        if let Some(mtime) = mtime {
+            let mtime = match mtime {
+                TimeOrNow::SpecificTime(time) => time,
+                TimeOrNow::Now => SystemTime::now(),
+            };
+            block.mtime = Some(mtime.duration_since(UNIX_EPOCH).map(|x| x.as_nanos() as i64).unwrap_or(0));
+            changed = true;
+        }
+        if let Some(mode) = mode {
+            block.mode = Some(mode & 0o7777);
+            changed = true;
+        }
+        if let Some(uid) = uid {
+            block.uid = Some(uid);
+            changed = true;
+        }
+        if let Some(gid) = gid {
+            block.gid = Some(gid);
+            changed = true;
+        }
+        if changed {
+            if let Err(e) = self.cache.update(req.uid(), ino, block) {
+                warn!("Unable to update inode {}: {}", ino, e);
+                reply.error(EIO);
+                return;
+            }
+        }
+        if let Some(handle) = fh.and_then(|x| self.handles.get_mut(&x)) {
+            handle.inode = self.cache.get_inode(ino).0; // ftruncate is seen by the handle's reads
         }
-        reply.attr(&TTL, &self.cache.get_inode(ino).0.to_file_attr())
+        reply.attr(&TTL, &self.attr(ino))
     }
 
-    fn mkdir(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, _mode: u32, _umask: u32, reply: ReplyEntry) {
-        self.cache.create(req.uid(), parent, name, Kind::Directory);
+    fn mkdir(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, mode: u32, umask: u32, reply: ReplyEntry) {
+        if let Some(e) = self.read_only(parent, name) {
+            reply.error(e);
+            return;
+        }
+        if let Err(e) = self.cache.create(req.uid(), req.gid(), parent, name, Kind::Directory, mode & !umask) {
+            warn!("Unable to create {:?}: {}", name, e);
+            reply.error(EIO);
+            return;
+        }
 
-        reply.entry(&TTL, &self.cache.get_inode(self.cache.find_child_node(parent, name).unwrap().ino).0.to_file_attr(), 0);
+        reply.entry(&TTL, &self.attr(self.cache.find_child_node(parent, name).unwrap().ino), 0);
+    }
+
+    fn symlink(&mut self, req: &Request<'_>, parent: u64, link_name: &OsStr, target: &Path, reply: ReplyEntry) {
+        if let Some(e) = self.read_only(parent, link_name) {
+            reply.error(e);
+            return;
+        }
+        if self.cache.find_child_node(parent, link_name).is_some() {
+            reply.error(EEXIST);
+            return;
+        }
+        if let Err(e) = self.cache.symlink(req.uid(), req.gid(), parent, link_name, target.as_os_str()) {
+            warn!("Unable to create {:?}: {}", link_name, e);
+            reply.error(EIO);
+            return;
+        }
+
+        reply.entry(&TTL, &self.attr(self.cache.find_child_node(parent, link_name).unwrap().ino), 0);
+    }
+
+    fn readlink(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyData) {
+        let Some(inode) = self.view(ino) else {
+            reply.error(EINVAL);
+            return;
+        };
+        if inode.get_file_type() != Symlink {
+            reply.error(EINVAL);
+            return;
+        }
+        reply.data(&inode.block.target);
+    }
+
+    fn link(&mut self, req: &Request<'_>, ino: u64, newparent: u64, newname: &OsStr, reply: ReplyEntry) {
+        self.refresh();
+        if history::is_virtual(ino) {
+            reply.error(EROFS);
+            return;
+        }
+        if let Some(e) = self.read_only(newparent, newname) {
+            reply.error(e);
+            return;
+        }
+        if self.cache.get_inode(ino).0.get_file_type() == Directory {
+            reply.error(EPERM);
+            return;
+        }
+        if self.cache.find_child_node(newparent, newname).is_some() {
+            reply.error(EEXIST);
+            return;
+        }
+        if self.flush_ino(ino).is_err() {
+            reply.error(EIO);
+            return;
+        }
+        if let Err(e) = self.cache.link(req.uid(), ino, newparent, newname) {
+            warn!("Unable to link {:?}: {}", newname, e);
+            reply.error(EIO);
+            return;
+        }
+        reply.entry(&TTL, &self.attr(ino), 0);
     }
 
     fn unlink(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
@@ -74,28 +558,289 @@ impl Filesystem for CFS {
         self._delete(req, parent, name, Directory, reply);
     }
 
-    fn read(&mut self, _req: &Request, ino: u64, _fh: u64, offset: i64, size: u32, _flags: i32, _lock: Option<u64>, reply: ReplyData) {
-        let mut file_view = self.cache.get_inode(ino).0;
-        reply.data(&file_view.read(offset, size));
+    fn rename(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, newparent: u64, newname: &OsStr, flags: u32, reply: ReplyEmpty) {
+        self.refresh();
+        if self.read_only(parent, name).is_some() || self.read_only(newparent, newname).is_some() {
+            reply.error(EROFS);
+            return;
+        }
+        let Some(node) = self.cache.find_child_node(parent, name) else {
+            reply.error(ENOENT);
+            return;
+        };
+        if self.cache.get_inode(newparent).0.get_file_type() != Directory {
+            reply.error(ENOTDIR);
+            return;
+        }
+        let target = self.cache.find_child_node(newparent, newname);
+        // the buffered versions would be published under the old parent
+        let flushed = self.flush_ino(node.ino).and_then(|_| match &target {
+            Some(target) => self.flush_ino(target.ino),
+            None => Ok(()),
+        });
+        if flushed.is_err() {
+            reply.error(EIO);
+            return;
+        }
+
+        if flags & RENAME_EXCHANGE != 0 {
+            let Some(target) = target else {
+                reply.error(ENOENT);
+                return;
+            };
+            if self.cache.is_ancestor(node.ino, newparent) || self.cache.is_ancestor(target.ino, parent) {
+                reply.error(EINVAL);
+                return;
+            }
+            // the two moves are separate blocks, so a failed second one undoes the first
+            if let Err(e) = self.cache.rename(req.uid(), &node, newparent, newname) {
+                warn!("Unable to rename {:?}: {}", name, e);
+                reply.error(EIO);
+                return;
+            }
+            if let Err(e) = self.cache.rename(req.uid(), &target, parent, name) {
+                warn!("Unable to rename {:?}: {}", newname, e);
+                let undone = match self.cache.find_child_node(newparent, newname) {
+                    Some(moved) => self.cache.rename(req.uid(), &moved, parent, name)
+                        .and_then(|_| self.cache.update(req.uid(), target.ino, target.block.clone())),
+                    None => Ok(()),
+                };
+                if let Err(e) = undone {
+                    warn!("Unable to undo the rename of {:?}: {}", name, e);
+                }
+                reply.error(EIO);
+                return;
+            }
+            reply.ok();
+            return;
+        }
+
+        if self.cache.is_ancestor(node.ino, newparent) {
+            reply.error(EINVAL); // a directory cannot be moved into itself
+            return;
+        }
+
+        if let Some(target) = target {
+            if flags & RENAME_NOREPLACE != 0 {
+                reply.error(EEXIST);
+                return;
+            }
+            if target.ino == node.ino {
+                reply.ok();
+                return;
+            }
+            match (node.get_file_type(), target.get_file_type()) {
+                (Directory, Directory) if self.cache.get_inode(target.ino).1.iter().any(|x| !x.is_deleted()) => {
+                    reply.error(ENOTEMPTY);
+                    return;
+                }
+                (Directory, Directory) => {}
+                (Directory, _) => {
+                    reply.error(ENOTDIR);
+                    return;
+                }
+                (_, Directory) => {
+                    reply.error(EISDIR);
+                    return;
+                }
+                _ => {}
+            }
+            // the renamed entry is newer than the tombstone and takes its place, the tombstone
+            // keeps the replaced entry in the history so it can be restored
+            if let Err(e) = self.cache.delete(req.uid(), &target) {
+                warn!("Unable to delete {:?}: {}", newname, e);
+                reply.error(EIO);
+                return;
+            }
+        }
+
+        match self.cache.rename(req.uid(), &node, newparent, newname) {
+            Ok(()) => reply.ok(),
+            Err(e) => {
+                warn!("Unable to rename {:?}: {}", name, e);
+                reply.error(EIO);
+            }
+        }
     }
 
-    fn write(&mut self, req: &Request<'_>, ino: u64, _fh: u64, offset: i64, data: &[u8], _write_flags: u32, _flags: i32, _lock_owner: Option<u64>, reply: ReplyWrite) {
-        let inode = self.cache.get_inode(ino).0;
-        debug!("write {}, offset: {}, len: {}", String::from_utf8(inode.block.filename.clone()).unwrap(), offset, data.len());
-        if inode.block.kind != Kind::RegularFile.into() {
+    fn open(&mut self, _req: &Request<'_>, ino: u64, flags: i32, reply: ReplyOpen) {
+        self.refresh();
+        let writable = flags & O_ACCMODE != O_RDONLY;
+        if history::is_virtual(ino) {
+            if writable {
+                reply.error(EROFS);
+            } else if let Some(version) = history::version(&self.cache, ino) {
+                reply.opened(self.insert_handle(ino, false, version), 0);
+            } else {
+                reply.error(ENOENT);
+            }
+            return;
+        }
+        if ino >= self.cache.num_inodes() {
             reply.error(ENOENT);
             return;
         }
-        let mut inode = self.cache.get_inode(ino).0;
-        inode.write(req.uid(), offset, data);
+        if writable && self.cache.get_inode(ino).0.get_file_type() != RegularFile {
+            reply.error(EISDIR);
+            return;
+        }
+        reply.opened(self.open_handle(ino, writable), 0);
+    }
+
+    fn read(&mut self, _req: &Request, ino: u64, fh: u64, offset: i64, size: u32, _flags: i32, _lock: Option<u64>, reply: ReplyData) {
+        let mut file_view = match (self.dirty.get(&ino), self.handles.get(&fh)) {
+            (None, Some(handle)) => handle.inode.clone(),
+            _ => self.get_inode(ino),
+        };
+        match file_view.read(offset, size) {
+            Ok(data) => reply.data(&data),
+            Err(e) => {
+                warn!("Unable to read inode {}: {}", ino, e);
+                reply.error(EIO);
+            }
+        }
+    }
+
+    fn write(&mut self, req: &Request<'_>, ino: u64, fh: u64, offset: i64, data: &[u8], _write_flags: u32, _flags: i32, _lock_owner: Option<u64>, reply: ReplyWrite) {
+        self.flush_expired();
+        if !self.handles.get(&fh).is_some_and(|x| x.writable && x.ino == ino) {
+            reply.error(EBADF);
+            return;
+        }
+        if self.get_inode(ino).block.kind != Kind::RegularFile.into() {
+            reply.error(ENOENT);
+            return;
+        }
+        // the first unpublished write starts from the latest version, not the one at open
+        let dirty = self.dirty.entry(ino).or_insert_with(|| Dirty {
+            inode: self.cache.get_inode(ino).0,
+            uid: req.uid(),
+            since: Instant::now(),
+        });
+        let inode = &mut dirty.inode;
+        debug!("write {}, offset: {}, len: {}", String::from_utf8(inode.block.filename.clone()).unwrap(), offset, data.len());
+        if let Err(e) = inode.write(offset, data) {
+            warn!("Unable to write inode {}: {}", ino, e);
+            reply.error(EIO);
+            return;
+        }
         inode.block.size = max((offset + data.len() as i64) as u64, inode.block.size);
+        inode.block.mtime = None;
+        dirty.uid = req.uid();
 
-        let block = inode.block.clone();
-        self.cache.update(req.uid(), ino, block);
+        if dirty.inode.dirty_bytes() >= self.write_back.max_bytes {
+            if let Err(e) = self.flush_ino(ino) {
+                warn!("Unable to flush inode {}: {}", ino, e);
+                reply.error(EIO);
+                return;
+            }
+        }
         reply.written(data.len() as u32);
     }
 
+    fn flush(&mut self, _req: &Request<'_>, ino: u64, _fh: u64, _lock_owner: u64, reply: ReplyEmpty) {
+        self.reply_flush(ino, reply);
+    }
+
+    fn release(&mut self, _req: &Request<'_>, ino: u64, fh: u64, _flags: i32, _lock_owner: Option<u64>, _flush: bool, reply: ReplyEmpty) {
+        self.reply_flush(ino, reply);
+        self.handles.remove(&fh);
+        // writes that failed to publish stay buffered and are retried by flush_expired or destroy
+        if !self.handles.values().any(|x| x.ino == ino) && self.cache.get_inode(ino).0.is_deleted() {
+            self.dirty.remove(&ino); // written to an unlinked file
+        }
+    }
+
+    // data and inode blocks are acknowledged by the middleware once flush_ino returns
+    fn fsync(&mut self, _req: &Request<'_>, ino: u64, _fh: u64, _datasync: bool, reply: ReplyEmpty) {
+        self.reply_flush(ino, reply);
+    }
+
+    // only the user namespace is stored, other namespaces such as security.* are reported as unsupported
+    fn setxattr(&mut self, req: &Request<'_>, ino: u64, name: &OsStr, value: &[u8], flags: i32, _position: u32, reply: ReplyEmpty) {
+        if history::is_virtual(ino) {
+            reply.error(EROFS);
+            return;
+        }
+        let Some(name) = name.to_str().filter(|x| x.starts_with(XATTR_USER_PREFIX)) else {
+            reply.error(ENOTSUP);
+            return;
+        };
+        if name == XATTR_RESTORE {
+            self.restore(req, ino, OsStr::from_bytes(value), reply);
+            return;
+        }
+        self.update_xattrs(req, ino, reply, |xattrs| {
+            if flags & XATTR_CREATE != 0 && xattrs.contains_key(name) {
+                return Err(EEXIST);
+            }
+            if flags & XATTR_REPLACE != 0 && !xattrs.contains_key(name) {
+                return Err(ENODATA);
+            }
+            xattrs.insert(name.to_string(), value.to_vec());
+            Ok(())
+        });
+    }
+
+    fn getxattr(&mut self, _req: &Request<'_>, ino: u64, name: &OsStr, size: u32, reply: ReplyXattr) {
+        let inode = self.view(ino);
+        let Some(value) = inode.as_ref().zip(name.to_str()).and_then(|(inode, x)| inode.block.xattrs.get(x)) else {
+            reply.error(ENODATA);
+            return;
+        };
+        if size == 0 {
+            reply.size(value.len() as u32);
+        } else if (size as usize) < value.len() {
+            reply.error(ERANGE);
+        } else {
+            reply.data(value);
+        }
+    }
+
+    fn listxattr(&mut self, _req: &Request<'_>, ino: u64, size: u32, reply: ReplyXattr) {
+        let mut names = vec![];
+        for name in self.view(ino).iter().flat_map(|x| x.block.xattrs.keys()) {
+            names.extend_from_slice(name.as_bytes());
+            names.push(0);
+        }
+        if size == 0 {
+            reply.size(names.len() as u32);
+        } else if (size as usize) < names.len() {
+            reply.error(ERANGE);
+        } else {
+            reply.data(&names);
+        }
+    }
+
+    fn removexattr(&mut self, req: &Request<'_>, ino: u64, name: &OsStr, reply: ReplyEmpty) {
+        if history::is_virtual(ino) {
+            reply.error(EROFS);
+            return;
+        }
+        let Some(name) = name.to_str().filter(|x| x.starts_with(XATTR_USER_PREFIX)) else {
+            reply.error(ENODATA);
+            return;
+        };
+        self.update_xattrs(req, ino, reply, |xattrs| {
+            xattrs.remove(name).map(|_| ()).ok_or(ENODATA)
+        });
+    }
+
     fn readdir(&mut self, _req: &Request, ino: u64, _fh: u64, offset: i64, mut reply: ReplyDirectory) {
+        self.refresh();
+        if history::is_virtual(ino) {
+            let Some(entries) = history::read_dir(&self.cache, ino) else {
+                reply.error(ENOENT);
+                return;
+            };
+            for (i, (ino, file_type, name)) in entries.into_iter().enumerate().skip(offset as usize) {
+                if reply.add(ino, (i + 1) as i64, file_type, OsStr::from_bytes(&name)) {
+                    break;
+                }
+            }
+            reply.ok();
+            return;
+        }
         let mut inode = self.cache.get_inode(ino).0;
         if inode.get_file_type() != Directory {
             reply.error(ENOENT);
@@ -103,6 +848,16 @@ impl Filesystem for CFS {
         }
 
         let mut children = self.cache.get_inode(ino).1;
+        if ino == 1 {
+            // shadows a real entry of the same name
+            children.retain(|x| x.block.filename != HISTORY_NAME.as_bytes());
+            if let Some(ino) = history::lookup(&self.cache, 1, OsStr::new(HISTORY_NAME)) {
+                let mut entry = inode.clone();
+                entry.ino = ino;
+                entry.block.filename = Vec::from(HISTORY_NAME);
+                children.push(entry);
+            }
+        }
 
         let mut parent_inode = self.cache.get_inode(self.cache.get_ino(inode.parent_hash.clone())).0;
         parent_inode.block.filename = Vec::from("..");
@@ -120,9 +875,19 @@ impl Filesystem for CFS {
         reply.ok();
     }
 
-    fn create(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, _mode: u32, _umask: u32, _flags: i32, reply: ReplyCreate) {
-        self.cache.create(req.uid(), parent, name, Kind::RegularFile);
-        reply.created(&TTL, &self.cache.get_inode(self.cache.find_child_node(parent, name).unwrap().ino).0.to_file_attr(), 0, 100, 0); // todo: file handle?
+    fn create(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, mode: u32, umask: u32, flags: i32, reply: ReplyCreate) {
+        if let Some(e) = self.read_only(parent, name) {
+            reply.error(e);
+            return;
+        }
+        if let Err(e) = self.cache.create(req.uid(), req.gid(), parent, name, Kind::RegularFile, mode & !umask) {
+            warn!("Unable to create {:?}: {}", name, e);
+            reply.error(EIO);
+            return;
+        }
+        let ino = self.cache.find_child_node(parent, name).unwrap().ino;
+        let fh = self.open_handle(ino, flags & O_ACCMODE != O_RDONLY);
+        reply.created(&TTL, &self.attr(ino), 0, fh, 0);
     }
 }
 
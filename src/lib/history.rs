@@ -0,0 +1,145 @@
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+
+use fuser::{FileAttr, FileType};
+
+use crate::cache::Cache;
+use crate::inode::INode;
+
+/* The read-only .cfs-history tree in the root directory. It mirrors the directories of the file
+ * system, and every file in it is a directory with one entry per version of that file, named
+ * after the time of the version and the uid of its author.
+ */
+pub const HISTORY_NAME: &str = ".cfs-history";
+
+// inos of the tree are derived from the inos of the real file system
+const DIR_BIT: u64 = 1 << 63;
+const VERSION_BIT: u64 = 1 << 62;
+const VERSION_SHIFT: u32 = 20; // at most 2^20 versions per file are shown
+
+pub enum Node {
+    Dir(u64), // ino of the mirrored directory or file
+    Version(u64, usize), // ino of the file and index into its history
+}
+
+pub fn is_virtual(ino: u64) -> bool {
+    return ino & (DIR_BIT | VERSION_BIT) != 0;
+}
+
+// an entry of the root directory that is hidden by the tree
+pub fn is_reserved(parent: u64, name: &OsStr) -> bool {
+    return parent == 1 && name == HISTORY_NAME;
+}
+
+pub fn parse(cache: &Cache, ino: u64) -> Option<Node> {
+    let node = if ino & DIR_BIT != 0 {
+        Node::Dir(ino & !DIR_BIT)
+    } else if ino & VERSION_BIT != 0 {
+        let ino = ino & !VERSION_BIT;
+        Node::Version(ino >> VERSION_SHIFT, (ino & ((1 << VERSION_SHIFT) - 1)) as usize)
+    } else {
+        return None;
+    };
+    return match node {
+        Node::Dir(real) | Node::Version(real, _) if real == 0 || real >= cache.num_inodes() => None,
+        node => Some(node),
+    };
+}
+
+fn dir_ino(real: u64) -> u64 {
+    return DIR_BIT | real;
+}
+
+fn version_ino(real: u64, index: usize) -> u64 {
+    return VERSION_BIT | (real << VERSION_SHIFT) | index as u64;
+}
+
+pub fn version(cache: &Cache, ino: u64) -> Option<INode> {
+    let Some(Node::Version(real, index)) = parse(cache, ino) else {
+        return None;
+    };
+    return cache.get_history(real).get(index).map(|(inode, _)| inode.clone());
+}
+
+pub fn attr(cache: &Cache, ino: u64) -> Option<FileAttr> {
+    return match parse(cache, ino)? {
+        Node::Dir(real) => {
+            let mut attr = cache.get_inode(real).0.to_file_attr();
+            attr.ino = ino;
+            attr.kind = FileType::Directory;
+            attr.perm = 0o555;
+            attr.nlink = 2;
+            attr.size = 0;
+            attr.blocks = 0;
+            Some(attr)
+        }
+        Node::Version(_, _) => {
+            let mut attr = version(cache, ino)?.to_file_attr();
+            attr.ino = ino;
+            attr.perm &= !0o222;
+            Some(attr)
+        }
+    };
+}
+
+// the entries of a directory of the tree, including "." and ".."
+pub fn read_dir(cache: &Cache, ino: u64) -> Option<Vec<(u64, FileType, Vec<u8>)>> {
+    let Some(Node::Dir(real)) = parse(cache, ino) else {
+        return None;
+    };
+    let (inode, children) = cache.get_inode(real);
+    let parent = if real == 1 { 1 } else { dir_ino(cache.get_ino(inode.parent_hash.clone())) };
+    let mut entries = vec![
+        (ino, FileType::Directory, Vec::from(".")),
+        (parent, FileType::Directory, Vec::from("..")),
+    ];
+
+    if inode.get_file_type() == FileType::Directory {
+        for child in children.iter().filter(|x| !x.is_deleted()) {
+            entries.push((dir_ino(child.ino), FileType::Directory, child.block.filename.clone()));
+        }
+    } else {
+        let mut versions = vec![];
+        for (index, (version, uid)) in cache.get_history(real).iter().enumerate().take(1 << VERSION_SHIFT) {
+            if !version.is_deleted() {
+                versions.push((version_ino(real, index), version.get_file_type(), version_name(version, *uid).into_bytes()));
+            }
+        }
+        versions.sort_by(|a, b| a.2.cmp(&b.2)); // oldest first
+        entries.extend(versions);
+    }
+    return Some(entries);
+}
+
+pub fn lookup(cache: &Cache, parent: u64, name: &OsStr) -> Option<u64> {
+    if is_reserved(parent, name) {
+        return Some(dir_ino(1));
+    }
+    return read_dir(cache, parent)?.into_iter()
+        .skip(2) // "." and ".."
+        .find(|(_, _, x)| OsStr::from_bytes(x) == name)
+        .map(|(ino, _, _)| ino);
+}
+
+fn version_name(version: &INode, uid: u64) -> String {
+    return format!("{}-uid{}", format_timestamp(version.timestamp), uid);
+}
+
+// nanoseconds since the epoch as an ISO 8601 date in UTC, see http://howardhinnant.github.io/date_algorithms.html
+fn format_timestamp(timestamp: i64) -> String {
+    let seconds = timestamp.div_euclid(1_000_000_000);
+    let nanos = timestamp.rem_euclid(1_000_000_000);
+    let time = seconds.rem_euclid(86400);
+
+    let z = seconds.div_euclid(86400) + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    return format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:09}Z", year, month, day, time / 3600, time % 3600 / 60, time % 60, nanos);
+}
@@ -1,9 +1,11 @@
+use std::cmp::{max, min};
 use std::collections::HashMap;
+use std::error::Error;
 use std::sync::Arc;
-use std::time::UNIX_EPOCH;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use fuser::{FileAttr, FileType};
-use fuser::FileType::Directory;
+use fuser::FileType::{Directory, Symlink};
 use futures::executor::block_on;
 use futures::future::join_all;
 use log::debug;
@@ -13,6 +15,11 @@ use crate::proto::block::{DataBlock, DataCapsuleFileSystemBlock, INodeBlock};
 use crate::proto::block::data_capsule_file_system_block::Block;
 use crate::proto::block::i_node_block::Kind;
 
+// hash of a block that has never been written, it reads as zeros
+pub const SPARSE_BLOCK: &str = "";
+// written blocks that are not published yet are kept in the journal under this prefix and their index
+const JOURNAL_PREFIX: &str = "journal-";
+
 #[derive(Clone)]
 pub struct INode {
     pub hash: String,
@@ -20,6 +27,7 @@ pub struct INode {
     pub parent_hash: String,
     pub block: INodeBlock,
     pub timestamp: i64,
+    pub created: i64, // timestamp of the first version
     pub block_client: Arc<BlockClient>,
     pub middleware_client: Option<Arc<FSMiddlewareClient>>,
     pub journal: HashMap<String, Vec<u8>>,
@@ -32,61 +40,80 @@ impl INode {
         FileAttr{
             ino: self.ino,
             size: self.block.size,
-            blocks: (self.block.size + self.block_size as u64 - 1) / self.block_size as u64, // round up
-            atime: UNIX_EPOCH, // 1970-01-01 00:00:00
-            mtime: UNIX_EPOCH,
-            ctime: UNIX_EPOCH,
-            crtime: UNIX_EPOCH,
+            blocks: self.block.size.div_ceil(self.block_size as u64),
+            atime: to_system_time(self.get_mtime()), // not tracked
+            mtime: to_system_time(self.get_mtime()),
+            ctime: to_system_time(self.timestamp),
+            crtime: to_system_time(self.created),
             kind: self.get_file_type(),
             perm: self.get_perm(),
-            nlink: 2,
-            uid: 1000,
-            gid: 1000,
+            nlink: 1, // the entries are counted by Cache::nlink
+            uid: self.block.uid.unwrap_or(1000),
+            gid: self.block.gid.unwrap_or(1000),
             rdev: 0,
             flags: 0,
             blksize: self.block_size as u32,
         }
     }
 
+    pub fn get_mtime(&self) -> i64 {
+        return self.block.mtime.unwrap_or(self.timestamp);
+    }
+
     pub fn get_file_type(&self) -> FileType {
         return if self.block.kind == Kind::Directory.into() {
             FileType::Directory
         } else if self.block.kind == Kind::RegularFile.into() {
             FileType::RegularFile
+        } else if self.block.kind == Kind::Symlink.into() {
+            FileType::Symlink
         } else {
             FileType::RegularFile // deleted file!!
         }
     }
 
     pub fn get_perm(&self) -> u16 {
+        if let Some(mode) = self.block.mode {
+            return (mode & 0o7777) as u16;
+        }
+        // blocks written before the mode was stored
         return if self.get_file_type() == Directory {
             0o700
+        } else if self.get_file_type() == Symlink {
+            0o777 // not checked for symlinks
         } else {
             0o700
         }
     }
 
     pub fn is_deleted(&self) -> bool {
-        return self.block.kind == Kind::DeletedFolder.into() || self.block.kind == Kind::DeletedRegularFile.into();
+        return self.block.kind == Kind::DeletedFolder.into() || self.block.kind == Kind::DeletedRegularFile.into() || self.block.kind == Kind::DeletedSymlink.into();
     }
 }
 
+// block timestamps are nanoseconds since the epoch
+fn to_system_time(timestamp: i64) -> SystemTime {
+    return UNIX_EPOCH + Duration::from_nanos(max(timestamp, 0) as u64);
+}
+
 impl INode {
-    async fn read_block(&self, idx: usize, offset: u64) -> Vec<u8> {
-        return if let Some(hash) = self.block.hashes.get(idx) {
-            if self.journal.contains_key(hash) {
+    async fn read_block(&self, idx: usize, offset: u64) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+        return Ok(if let Some(hash) = self.block.hashes.get(idx) {
+            if hash == SPARSE_BLOCK {
+                vec![0u8; self.block_size - offset as usize]
+            } else if self.journal.contains_key(hash) {
                 let block = self.journal.get(hash).cloned().unwrap();
                 block[offset as usize..].to_vec()
             } else {
-                let response = self.block_client.get_block(hash.clone()).await.unwrap();
+                let response = self.block_client.get_block(hash.clone(), &self.prev_data_hash).await?;
                 response[offset as usize..].to_vec()
             }
         } else {
             vec![]
-        }
+        });
     }
 
-    async fn write_block(&self, uid: u32, data: Vec<u8>) -> String {
+    async fn write_block(&self, uid: u32, data: Vec<u8>) -> Result<String, Box<dyn Error + Send + Sync>> {
         let data = DataBlock { data };
 
         let block = DataCapsuleFileSystemBlock {
@@ -96,87 +123,118 @@ impl INode {
             signature: vec![],
         };
 
-        self.middleware_client.clone().unwrap().put_data(block, self.parent_hash.clone()).await.unwrap().hash.unwrap()
+        Ok(self.middleware_client.clone().unwrap().put_data(block, self.parent_hash.clone()).await?.hash.unwrap())
     }
 
-    pub fn read(&mut self, offset: i64, size: u32) -> Vec<u8> {
-        let mut current = offset as usize / self.block_size;
-        let mut total_read_bytes = 0;
-        let mut data = vec![];
-
-        // read partial block first
-        debug!("Getting partial block {} for offset {} size {}\n", current, offset, size);
-        let mut blocks = vec![];
-
-        let partial_block = self.read_block(current, (offset as usize % self.block_size) as u64);
-        blocks.push(partial_block);
-        total_read_bytes += self.block_size - (offset as usize % self.block_size);
-        current += 1;
-
-        // the until everything is read
-        while total_read_bytes < size as usize {
-            debug!("Getting full {} block for offset {} size {}\n", current, offset, size);
-            let block = self.read_block(current, 0);
-            blocks.push(block);
-            total_read_bytes += self.block_size;
-            current += 1;
+    pub fn read(&mut self, offset: i64, size: u32) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+        if offset as u64 >= self.block.size {
+            return Ok(vec![]);
+        }
+        let size = min(size as u64, self.block.size - offset as u64) as u32;
+        let first = offset as usize / self.block_size;
+        let last = min((offset as usize + size as usize).div_ceil(self.block_size), self.block.hashes.len());
+        if first >= last {
+            return Ok(vec![]);
         }
 
-        let results = block_on(async {
-            join_all(blocks).await
-        });
+        // fetch every block of the range that is not in the journal in one round trip
+        debug!("Getting blocks {}..{} for offset {} size {}\n", first, last, offset, size);
+        let hashes = self.block.hashes[first..last].iter()
+            .filter(|x| *x != SPARSE_BLOCK && !self.journal.contains_key(*x))
+            .cloned()
+            .collect();
+        let blocks = block_on(self.block_client.get_blocks(hashes, &self.prev_data_hash))?;
 
-        for block in results {
-            data.extend_from_slice(&block);
+        let mut data = vec![];
+        for hash in &self.block.hashes[first..last] {
+            if hash == SPARSE_BLOCK {
+                data.extend_from_slice(&vec![0u8; self.block_size]);
+            } else if let Some(block) = self.journal.get(hash) {
+                data.extend_from_slice(block);
+            } else {
+                data.extend_from_slice(&blocks[hash]);
+            }
         }
 
-        return data;
+        let start = min(offset as usize % self.block_size, data.len());
+        let end = min(start + size as usize, data.len());
+        return Ok(data[start..end].to_vec());
     }
 
-    pub fn write(&mut self, uid: u32, offset: i64, data: &[u8]) {
-        let mut block_id = offset as usize / self.block_size;
-        while self.block.hashes.len() <= block_id { // 0 fill if offset is past EOF
-            let hash = block_on(self.write_block(uid, vec![0u8; self.block_size as usize]));
-            self.block.hashes.push(hash);
+    // buffers the data in the journal, nothing is published until flush
+    pub fn write(&mut self, offset: i64, data: &[u8]) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let first = offset as usize / self.block_size;
+        let last = (offset as usize + data.len()).div_ceil(self.block_size);
+
+        // blocks that are only partially overwritten keep the rest of their content
+        let hashes: Vec<String> = self.block.hashes.iter().take(last).skip(first)
+            .filter(|x| *x != SPARSE_BLOCK && !self.journal.contains_key(*x))
+            .cloned()
+            .collect();
+        debug!("Getting {} blocks to write offset {} len {}", hashes.len(), offset, data.len());
+        let blocks = block_on(self.block_client.get_blocks(hashes, &self.prev_data_hash))?;
+
+        while self.block.hashes.len() < last { // 0 fill if offset is past EOF
+            self.block.hashes.push(SPARSE_BLOCK.into());
         }
 
         let mut next = 0;
-        // read partial block first
-        debug!("Getting partial block {} for offset {}\n", block_id, offset);
-        let mut block = block_on(self.read_block(block_id, 0));
-        block.truncate(offset as usize % self.block_size);
-
-        while next < data.len() {
-            let remaining_bytes = self.block_size as usize - block.len();
-            if data.len() >= next + remaining_bytes { // enough bytes to fill all
-                block.extend_from_slice(&data[next..(next + remaining_bytes)]);
-            } else { // need to check if we still have existing data
-                block.extend_from_slice(&data[next..]);
-                if block_id < self.block.hashes.len() {
-                    debug!("Getting partial block {} for offset {}\n", block_id, offset);
-                    let prev_block = block_on(self.read_block(block_id, block.len() as u64));
-                    block.extend_from_slice(&prev_block)
-                }
+        let mut start = offset as usize % self.block_size;
+        for block_id in first..last {
+            let key = format!("{}{}", JOURNAL_PREFIX, block_id);
+            if !self.journal.contains_key(&key) {
+                let hash = &self.block.hashes[block_id];
+                let mut block = if hash == SPARSE_BLOCK { vec![] } else { blocks[hash].clone() };
+                block.resize(self.block_size, 0);
+                self.journal.insert(key.clone(), block);
+                self.block.hashes[block_id] = key.clone();
             }
 
-            if block.len() != self.block_size as usize {
-                debug!("Fill block with 0s");
-                block.extend_from_slice(&vec![0u8; self.block_size as usize - block.len()]);
-            }
+            let len = min(self.block_size - start, data.len() - next);
+            self.journal.get_mut(&key).unwrap()[start..start + len].copy_from_slice(&data[next..next + len]);
+            next += len;
+            start = 0;
+        }
+        Ok(())
+    }
 
-            debug!("Publish block len = {}", block.len());
-            let hash = block_on(self.write_block(uid, block));
+    pub fn dirty_bytes(&self) -> usize {
+        return self.journal.len() * self.block_size;
+    }
 
-            if block_id < self.block.hashes.len() { // within bounds, replace existing
-                self.block.hashes[block_id] = hash;
-            } else {
-                self.block.hashes.push(hash);
-            }
+    // publishes the journal in parallel and points the hashes at the published blocks, nothing changes if one fails
+    pub fn flush(&mut self, uid: u32) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let dirty: Vec<usize> = (0..self.block.hashes.len())
+            .filter(|x| self.journal.contains_key(&self.block.hashes[*x]))
+            .collect();
+        debug!("Flushing {} blocks", dirty.len());
+        let published = block_on(join_all(dirty.iter()
+            .map(|x| self.write_block(uid, self.journal[&self.block.hashes[*x]].clone()))));
+        let published = published.into_iter().collect::<Result<Vec<String>, _>>()?;
+        for (block_id, hash) in dirty.into_iter().zip(published) {
+            self.block.hashes[block_id] = hash;
+        }
+        self.journal.clear();
+        Ok(())
+    }
 
-            next += remaining_bytes;
-            block_id += 1;
-            block = vec![];
+    // drops the blocks past the new size, growing the file adds sparse blocks
+    pub fn truncate(&mut self, uid: u32, size: u64) -> Result<(), Box<dyn Error + Send + Sync>> {
+        // zero the rest of the last block, so the old bytes do not reappear when the file grows again
+        let end = min(size, self.block.size);
+        let block_id = end as usize / self.block_size;
+        let tail = end as usize % self.block_size;
+        if tail != 0 && self.block.hashes.get(block_id).is_some_and(|x| x != SPARSE_BLOCK) {
+            let mut block = block_on(self.read_block(block_id, 0))?;
+            block.truncate(tail);
+            block.resize(self.block_size, 0);
+            self.block.hashes[block_id] = block_on(self.write_block(uid, block))?;
         }
+
+        let count = (size as usize).div_ceil(self.block_size);
+        self.block.hashes.resize(count, SPARSE_BLOCK.into());
+        self.block.size = size;
+        Ok(())
     }
 
     // pub fn write(&mut self, uid: u32, offset: i64, data: &[u8]) {
@@ -1,7 +1,14 @@
+#![allow(clippy::needless_return)] // explicit returns are the style of this crate
+
 pub mod client;
 pub mod fs;
 pub mod server;
 pub mod proto;
 pub mod cache;
 pub mod crypto;
-pub mod inode;
\ No newline at end of file
+pub mod inode;
+pub mod wal;
+pub mod replication;
+pub mod history;
+#[cfg(test)]
+mod test_util;
\ No newline at end of file
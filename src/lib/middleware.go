@@ -49,7 +49,7 @@ func (s *MiddlewareServer) PutINode(ctx context.Context, in *pb.PutINodeRequest)
 	block := s.finalizeBlock(in.Block, s.InodeSigningKey)
 	result, err := s.InodeClient.Put(ctx, &pb.PutRequest{Block: block})
 	if err != nil {
-		panic(err)
+		return nil, err
 	}
 
 	return &pb.PutINodeResponse{
@@ -84,7 +84,7 @@ func (s *MiddlewareServer) PutData(ctx context.Context, in *pb.PutDataRequest) (
 	block := s.finalizeBlock(in.Block, s.DataSigningKey)
 	result, err := s.DataClient.Put(ctx, &pb.PutRequest{Block: block})
 	if err != nil {
-		panic(err)
+		return nil, err
 	}
 
 	return &pb.PutDataResponse{
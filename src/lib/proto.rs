@@ -1,3 +1,4 @@
+#[allow(clippy::large_enum_variant)]
 pub mod block {
     tonic::include_proto!("block");
 }
@@ -0,0 +1,141 @@
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::warn;
+use tokio::sync::mpsc;
+use tonic::transport::{Channel, ClientTlsConfig, Endpoint};
+
+use crate::crypto::SignableBlock;
+use crate::proto::block::DataCapsuleBlock;
+use crate::proto::data_capsule::{BatchGetRequest, LeafsRequest, PutRequest};
+use crate::proto::data_capsule::data_capsule_client::DataCapsuleClient;
+use crate::server::{BATCH_GET_LIMIT, MyDataCapsule};
+
+/* The other servers hosting the same capsule. Blocks are forwarded to each peer through its own
+ * queue, so a peer receives them in the order they were accepted.
+ */
+#[derive(Debug)]
+pub struct Replicator {
+    peers: Vec<(String, DataCapsuleClient<Channel>)>,
+    queues: Vec<mpsc::UnboundedSender<DataCapsuleBlock>>,
+}
+
+impl Replicator {
+    // must be called within a tokio runtime, which runs the queues
+    pub fn new(urls: Vec<String>, tls_config: ClientTlsConfig) -> Replicator {
+        let peers: Vec<(String, DataCapsuleClient<Channel>)> = urls.into_iter().map(|url| {
+            // connect lazily, peers that are down must not keep this server from starting
+            let channel = Endpoint::from_shared(url.clone()).unwrap()
+                .tls_config(tls_config.clone()).unwrap()
+                .connect_lazy();
+            (url, DataCapsuleClient::new(channel))
+        }).collect();
+        let queues = peers.iter().map(|(url, client)| {
+            let (sender, receiver) = mpsc::unbounded_channel();
+            tokio::spawn(Replicator::forward_in_order(url.clone(), client.clone(), receiver));
+            sender
+        }).collect();
+        Replicator { peers, queues }
+    }
+
+    // whatever a peer misses is caught up by the next anti-entropy pass
+    async fn forward_in_order(url: String, mut client: DataCapsuleClient<Channel>, mut queue: mpsc::UnboundedReceiver<DataCapsuleBlock>) {
+        while let Some(block) = queue.recv().await {
+            if let Err(e) = client.put(tonic::Request::new(PutRequest { block: Some(block) })).await {
+                warn!("Unable to forward block to {}: {}", url, e);
+            }
+        }
+    }
+
+    /* Queues the block for every peer. Has to be called in the order blocks are accepted, i.e.
+     * while holding the storage lock.
+     */
+    pub fn forward(&self, block: &DataCapsuleBlock) {
+        for queue in &self.queues {
+            let _ = queue.send(block.clone()); // fails only if the runtime is shutting down
+        }
+    }
+
+    // fetches the blocks a peer has but we do not, starting from its leafs and following prev_hash
+    async fn missing_blocks(capsule: &MyDataCapsule, client: &mut DataCapsuleClient<Channel>) -> Result<HashMap<String, DataCapsuleBlock>, Box<dyn Error + Send + Sync>> {
+        let leafs = client.leafs(tonic::Request::new(LeafsRequest {})).await?.into_inner().leaf_ids;
+
+        let mut missing = HashMap::new();
+        let mut pending = {
+            let storage = capsule.storage.lock().await;
+            leafs.into_iter().filter(|x| !storage.contains(x)).collect::<HashSet<String>>()
+        };
+        while !pending.is_empty() {
+            let hashes: Vec<String> = pending.drain().collect();
+            let mut blocks = HashMap::new();
+            for chunk in hashes.chunks(BATCH_GET_LIMIT) {
+                blocks.extend(client.batch_get(tonic::Request::new(BatchGetRequest { block_hashes: chunk.to_vec() })).await?.into_inner().blocks);
+            }
+            let storage = capsule.storage.lock().await;
+            for (hash, block) in blocks {
+                if block.hash() != hash {
+                    warn!("Peer returned a different block for {}", hash);
+                    continue;
+                }
+                let prev_hash = block.prev_hash.clone();
+                missing.insert(hash, block);
+                if !prev_hash.is_empty() && !storage.contains(&prev_hash) && !missing.contains_key(&prev_hash) {
+                    pending.insert(prev_hash);
+                }
+            }
+        }
+        Ok(missing)
+    }
+}
+
+/* Periodically pulls the blocks that peers have accepted while this server was down or missed
+ * their forwarded puts.
+ */
+pub async fn anti_entropy(capsule: Arc<MyDataCapsule>, period: Duration) {
+    let Some(replicator) = &capsule.replicator else {
+        return;
+    };
+    let mut interval = tokio::time::interval(period);
+    loop {
+        interval.tick().await;
+        for (url, client) in &replicator.peers {
+            let mut client = client.clone();
+            let missing = match Replicator::missing_blocks(&capsule, &mut client).await {
+                Ok(missing) => missing,
+                Err(e) => {
+                    warn!("Anti-entropy with {} failed: {}", url, e);
+                    continue;
+                }
+            };
+            if missing.is_empty() {
+                continue;
+            }
+
+            // parents have to be accepted before their children
+            let mut depths = HashMap::new();
+            for hash in missing.keys() {
+                let mut depth = 0;
+                let mut next = &missing[hash].prev_hash;
+                while let Some(block) = missing.get(next) {
+                    depth += 1;
+                    next = &block.prev_hash;
+                }
+                depths.insert(hash.clone(), depth);
+            }
+            let mut blocks: Vec<(String, DataCapsuleBlock)> = missing.into_iter().collect();
+            blocks.sort_by_key(|(hash, _)| depths[hash]);
+
+            let mut count = 0;
+            for (hash, block) in blocks {
+                match capsule.accept(block).await {
+                    Ok(Some(_)) => count += 1,
+                    Ok(None) => {}
+                    Err(e) => warn!("Rejected block {} from {}: {}", hash, url, e),
+                }
+            }
+            println!("Pulled {} blocks from {}", count, url);
+        }
+    }
+}
@@ -1,69 +1,634 @@
+use std::collections::HashSet;
+use std::fmt::Debug;
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
 use std::sync::Arc;
 
+use futures::Stream;
+use prost::Message;
 use rsa::pkcs1v15::VerifyingKey;
 use rsa::sha2::Sha256;
-use tokio::sync::Mutex;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::broadcast::Sender;
+use tokio::sync::{watch, Mutex};
 use tonic::{Request, Response, Status};
-use crate::crypto::SignableBlock;
-
-use crate::proto::data_capsule::{DataCapsuleServerData, GetRequest, GetResponse, LeafsRequest, LeafsResponse, PutRequest, PutResponse};
+use crate::crypto::{is_allowed_writer, SignableBlock, validate_file_system_block};
+use crate::proto::block::DataCapsuleBlock;
+use crate::proto::data_capsule::{BatchGetRequest, BatchGetResponse, DataCapsuleServerData, GetRequest, GetResponse, LeafsRequest, LeafsResponse, PutRequest, PutResponse, SnapshotRequest, SnapshotResponse, StatsRequest, StatsResponse, SubscribeRequest, SubscribeResponse};
 use crate::proto::data_capsule::data_capsule_server::DataCapsule;
+use crate::replication::Replicator;
+use crate::wal;
+use crate::wal::WriteAheadLog;
+
+/* Where a DataCapsule keeps its blocks. Implementations must be durable once insert returns Ok,
+ * unless they are explicitly memory-only.
+ */
+pub trait Storage: Debug + Send + Sync {
+    fn get(&self, hash: &str) -> Option<DataCapsuleBlock>;
+    fn contains(&self, hash: &str) -> bool;
+    fn insert(&mut self, hash: String, block: DataCapsuleBlock) -> io::Result<()>;
+    fn leafs(&self) -> Vec<String>;
+    fn iter(&self) -> Box<dyn Iterator<Item = (String, DataCapsuleBlock)> + '_>;
+    fn snapshot(&mut self) -> io::Result<()>;
+    fn usage(&self) -> (u64, u64); // number of blocks and their total encoded size
+}
+
+// a BatchGet response is cut short once its blocks exceed this, clients ask again for the rest
+const MAX_BATCH_GET_BYTES: usize = 2 * 1024 * 1024;
+pub const BATCH_GET_LIMIT: usize = 32; // hashes clients ask for per BatchGet, so responses stay below the message size limit
+
+// limits on what a capsule may hold, None means unlimited
+#[derive(Debug, Default, Clone)]
+pub struct Quota {
+    pub max_blocks: Option<u64>,
+    pub max_bytes: Option<u64>,
+}
 
 #[derive(Debug)]
 pub struct MyDataCapsule {
-    pub data: Arc<Mutex<DataCapsuleServerData>>,
+    pub storage: Arc<Mutex<Box<dyn Storage>>>,
+    pub updates: Sender<(String, DataCapsuleBlock)>, // newly accepted blocks, for subscribers
+    pub replicator: Option<Replicator>,
+    pub quota: Quota,
     pub verifying_key: VerifyingKey<Sha256>,
     pub enable_crypto: bool,
+    pub admin_token: Option<String>, // required by the admin RPCs, which are disabled without it
+    pub shutdown: watch::Receiver<bool>, // ends the subscriptions, so a graceful shutdown does not wait for them
+}
+
+impl MyDataCapsule {
+    /* Validates and stores a block, then hands it to subscribers and peers without waiting for them.
+     * Returns the hash of the block, or None if it was already stored.
+     */
+    pub async fn accept(&self, mut block: DataCapsuleBlock) -> Result<Option<String>, Status> {
+        if self.enable_crypto {
+            if !block.validate(&self.verifying_key) {
+                return Err(Status::unauthenticated("Invalid signature of the block"));
+            }
+            if !block.fs.as_mut().is_some_and(validate_file_system_block) {
+                return Err(Status::unauthenticated("Invalid signature of the file system block or its author"));
+            }
+        }
+        match &block.fs {
+            Some(fs) if fs.prev_hash == block.prev_hash => {}
+            Some(_) => return Err(Status::invalid_argument("prevHash of the block differs from that of its file system block")),
+            None => return Err(Status::invalid_argument("Missing file system block")),
+        }
+        let hash = block.hash();
+
+        let mut storage = self.storage.lock().await;
+
+        if !storage.contains(&block.prev_hash) && !is_genesis(&**storage, &block) {
+            return Err(Status::failed_precondition(format!("Unknown prevHash {}", block.prev_hash)));
+        }
+        if self.enable_crypto {
+            let parent = storage.get(&block.prev_hash).and_then(|x| x.fs);
+            if parent.is_some_and(|parent| !is_allowed_writer(block.fs.as_ref().unwrap(), &parent)) {
+                return Err(Status::permission_denied("The author may not write to the parent of the block"));
+            }
+        }
+
+        if storage.contains(&hash) {
+            return Ok(None);
+        }
+        let (blocks, bytes) = storage.usage();
+        if self.quota.max_blocks.is_some_and(|max| blocks + 1 > max)
+            || self.quota.max_bytes.is_some_and(|max| bytes + block.encoded_len() as u64 > max) {
+            return Err(Status::resource_exhausted("Quota of the capsule exceeded"));
+        }
+        if let Err(e) = storage.insert(hash.clone(), block.clone()) {
+            return Err(Status::internal(format!("Unable to persist block: {}", e)));
+        }
+        if let Some(replicator) = &self.replicator {
+            replicator.forward(&block);
+        }
+        drop(storage);
+
+        let _ = self.updates.send((hash.clone(), block)); // fails only if nobody is subscribed
+        Ok(Some(hash))
+    }
+
+    // admin requests carry the configured token as "authorization: Bearer <token>"
+    #[allow(clippy::result_large_err)] // the Status is returned to the client as is
+    fn check_admin<T>(&self, request: &Request<T>) -> Result<(), Status> {
+        let Some(token) = &self.admin_token else {
+            return Err(Status::permission_denied("Admin requests are disabled"));
+        };
+        let provided = request.metadata().get("authorization")
+            .and_then(|x| x.to_str().ok())
+            .and_then(|x| x.strip_prefix("Bearer "));
+        match provided {
+            Some(provided) if constant_time_eq(provided.as_bytes(), token.as_bytes()) => Ok(()),
+            _ => Err(Status::unauthenticated("Invalid admin token")),
+        }
+    }
 }
 
 #[tonic::async_trait]
 impl DataCapsule for MyDataCapsule {
+    type SubscribeStream = Pin<Box<dyn Stream<Item = Result<SubscribeResponse, Status>> + Send>>;
+
     async fn get(&self, request: Request<GetRequest>) -> Result<Response<GetResponse>, Status> {
         println!("Got a get request: {:?}", request.get_ref().block_hash);
         let reply = GetResponse {
-            block: self.data.lock().await.content.get(&request.into_inner().block_hash).cloned()
+            block: self.storage.lock().await.get(&request.into_inner().block_hash)
         };
         Ok(Response::new(reply))
     }
 
+    async fn batch_get(&self, request: Request<BatchGetRequest>) -> Result<Response<BatchGetResponse>, Status> {
+        println!("Got a batch get request for {} blocks", request.get_ref().block_hashes.len());
+        let storage = self.storage.lock().await;
+        let mut reply = BatchGetResponse::default();
+        let mut bytes = 0;
+        for hash in request.into_inner().block_hashes {
+            let Some(block) = storage.get(&hash) else {
+                continue;
+            };
+            bytes += block.encoded_len();
+            if bytes > MAX_BATCH_GET_BYTES && !reply.blocks.is_empty() {
+                break;
+            }
+            reply.blocks.insert(hash, block);
+        }
+        Ok(Response::new(reply))
+    }
+
     async fn put(&self, request: Request<PutRequest>) -> Result<Response<PutResponse>, Status> {
         println!("Got a put request.");
 
         let request = request.into_inner();
-        let mut block = request.block.unwrap();
-        if self.enable_crypto {
-            block.validate(&self.verifying_key);
-        }
-        let hash = block.hash();
-
-        let mut mutex = self.data.lock().await;
-
-        if mutex.content.contains_key(&hash) {
-            Ok(Response::new(PutResponse {
+        let block = request.block.ok_or(Status::invalid_argument("Missing block"))?;
+        // durable here once accepted, the peers catch up through their queues and anti-entropy
+        let hash = self.accept(block).await?;
+        let reply = match hash {
+            Some(hash) => PutResponse {
+                success: true,
+                hash
+            },
+            None => PutResponse {
                 success: false,
                 hash: "".into()
-            }))
-        } else {
-            let prev_hash = block.prev_hash.clone();
-            mutex.content.insert(hash.clone(), block);
-            mutex.leafs.push(hash.clone());
+            },
+        };
+        Ok(Response::new(reply))
+    }
+
+    async fn leafs(&self, _request: Request<LeafsRequest>) -> Result<Response<LeafsResponse>, Status> {
+        let reply = LeafsResponse {
+            leaf_ids: self.storage.lock().await.leafs()
+        };
+        Ok(Response::new(reply))
+    }
+
+    async fn subscribe(&self, request: Request<SubscribeRequest>) -> Result<Response<Self::SubscribeStream>, Status> {
+        println!("Got a subscribe request.");
+        let include_block = request.into_inner().include_block;
+        let receiver = self.updates.subscribe();
+        let shutdown = self.shutdown.clone();
+        if *shutdown.borrow() {
+            return Err(Status::unavailable("Shutting down"));
+        }
 
-            let index = mutex.leafs.iter().position(|x| *x == prev_hash);
-            if index.is_some() {
-                mutex.leafs.remove(index.unwrap());
+        let stream = futures::stream::unfold((receiver, shutdown), move |(mut receiver, mut shutdown)| async move {
+            let result = tokio::select! {
+                result = receiver.recv() => result,
+                _ = shutdown.changed() => return None,
+            };
+            match result {
+                Ok((hash, block)) => Some((Ok(SubscribeResponse {
+                    hash,
+                    block: if include_block { Some(block) } else { None },
+                }), (receiver, shutdown))),
+                // the subscriber has missed blocks, end the stream so it re-syncs from the leafs
+                Err(RecvError::Lagged(count)) => Some((Err(Status::data_loss(format!("Missed {} blocks", count))), (receiver, shutdown))),
+                Err(RecvError::Closed) => None,
             }
+        });
+        Ok(Response::new(Box::pin(stream)))
+    }
 
-            Ok(Response::new(PutResponse {
-                success: true,
-                hash
-            }))
+    async fn snapshot(&self, request: Request<SnapshotRequest>) -> Result<Response<SnapshotResponse>, Status> {
+        println!("Got a snapshot request.");
+        self.check_admin(&request)?;
+        if let Err(e) = self.storage.lock().await.snapshot() {
+            return Err(Status::internal(format!("Unable to write snapshot: {}", e)));
         }
+        Ok(Response::new(SnapshotResponse {}))
     }
 
-    async fn leafs(&self, _request: Request<LeafsRequest>) -> Result<Response<LeafsResponse>, Status> {
-        let reply = LeafsResponse {
-            leaf_ids: self.data.lock().await.leafs.clone()
+    async fn stats(&self, _request: Request<StatsRequest>) -> Result<Response<StatsResponse>, Status> {
+        let (block_count, total_bytes) = self.storage.lock().await.usage();
+        let reply = StatsResponse {
+            block_count,
+            total_bytes,
+            max_blocks: self.quota.max_blocks,
+            max_bytes: self.quota.max_bytes,
         };
         Ok(Response::new(reply))
     }
-}
\ No newline at end of file
+}
+
+// compares without revealing the length of the common prefix through timing
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+// an fsync of the directory makes a rename or a new file in it durable
+fn sync_parent(path: &Path) -> io::Result<()> {
+    let parent = path.parent().filter(|x| !x.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    fs::File::open(parent)?.sync_all()
+}
+
+// only the first block of an empty capsule may start a new chain
+fn is_genesis(storage: &dyn Storage, block: &DataCapsuleBlock) -> bool {
+    block.prev_hash.is_empty() && storage.iter().next().is_none()
+}
+
+pub fn apply_block(data: &mut DataCapsuleServerData, hash: String, block: DataCapsuleBlock) {
+    add_leaf(&mut data.leafs, hash.clone(), &block.prev_hash);
+    data.content.insert(hash, block);
+}
+
+// the new block replaces its predecessor as a leaf, every storage keeps its leafs this way
+fn add_leaf(leafs: &mut Vec<String>, hash: String, prev_hash: &str) {
+    leafs.push(hash);
+    let index = leafs.iter().position(|x| *x == prev_hash);
+    if let Some(index) = index {
+        leafs.remove(index);
+    }
+}
+
+/* Keeps the whole capsule in a DataCapsuleServerData, optionally backed by a write-ahead log.
+ */
+#[derive(Debug)]
+pub struct MemoryStorage {
+    data: DataCapsuleServerData,
+    data_file: String,
+    log: Option<WriteAheadLog>,
+    bytes: u64,
+}
+
+impl MemoryStorage {
+    pub fn open(data_file: &str, log_file: Option<&str>) -> io::Result<MemoryStorage> {
+        let mut data = DataCapsuleServerData::decode(fs::read(data_file)?.as_slice())?;
+        let log = match log_file {
+            Some(log_file) => Some(wal::recover(&mut data, log_file)?),
+            None => None,
+        };
+        let bytes = data.content.values().map(|x| x.encoded_len() as u64).sum();
+        Ok(MemoryStorage { data, data_file: data_file.to_string(), log, bytes })
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn get(&self, hash: &str) -> Option<DataCapsuleBlock> {
+        self.data.content.get(hash).cloned()
+    }
+
+    fn contains(&self, hash: &str) -> bool {
+        self.data.content.contains_key(hash)
+    }
+
+    fn insert(&mut self, hash: String, block: DataCapsuleBlock) -> io::Result<()> {
+        if let Some(log) = &mut self.log {
+            log.append(&block)?;
+        }
+        self.bytes += block.encoded_len() as u64;
+        apply_block(&mut self.data, hash, block);
+        Ok(())
+    }
+
+    fn leafs(&self) -> Vec<String> {
+        self.data.leafs.clone()
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (String, DataCapsuleBlock)> + '_> {
+        Box::new(self.data.content.iter().map(|(hash, block)| (hash.clone(), block.clone())))
+    }
+
+    // write to a temporary file and rename it over the data file, so a crash never leaves a partial snapshot
+    fn snapshot(&mut self) -> io::Result<()> {
+        let mut buf = vec![];
+        self.data.encode(&mut buf).unwrap();
+
+        let tmp_file = format!("{}.tmp", self.data_file);
+        let mut file = fs::File::create(&tmp_file)?;
+        file.write_all(&buf)?;
+        file.sync_all()?;
+        fs::rename(&tmp_file, &self.data_file)?;
+        sync_parent(Path::new(&self.data_file))?;
+
+        // everything in the log is now part of the data file
+        if let Some(log) = &mut self.log {
+            log.truncate()?;
+        }
+        println!("Written snapshot of {} blocks to {}", self.data.content.len(), self.data_file);
+        Ok(())
+    }
+
+    fn usage(&self) -> (u64, u64) {
+        (self.data.content.len() as u64, self.bytes)
+    }
+}
+
+const LEAFS_FILE: &str = "leafs.txt"; // has an extension, so it is not taken for a block
+
+/* Stores every block in its own file named after its hash. Only the leafs are kept in memory,
+ * they are written to LEAFS_FILE after every block.
+ */
+#[derive(Debug)]
+pub struct DirectoryStorage {
+    path: PathBuf,
+    leafs: Vec<String>,
+    blocks: u64,
+    bytes: u64,
+}
+
+impl DirectoryStorage {
+    // `seed` is imported when the directory holds no blocks yet, e.g. the output of gen
+    pub fn open(path: &str, seed: Option<&str>) -> io::Result<DirectoryStorage> {
+        fs::create_dir_all(path)?;
+        let mut storage = DirectoryStorage {
+            path: PathBuf::from(path),
+            leafs: Vec::new(),
+            blocks: 0,
+            bytes: 0,
+        };
+
+        let mut hashes = HashSet::new();
+        let mut referenced = HashSet::new();
+        let mut bytes = 0;
+        for (hash, block) in storage.iter() {
+            bytes += block.encoded_len() as u64;
+            referenced.insert(block.prev_hash);
+            hashes.insert(hash);
+        }
+        storage.blocks = hashes.len() as u64;
+        storage.bytes = bytes;
+
+        if hashes.is_empty() {
+            if let Some(seed) = seed {
+                let data = DataCapsuleServerData::decode(fs::read(seed)?.as_slice())?;
+                for (hash, block) in data.content {
+                    storage.write_block(&hash, &block)?;
+                    storage.blocks += 1;
+                    storage.bytes += block.encoded_len() as u64;
+                }
+                storage.leafs = data.leafs;
+                println!("Imported {} into {}", seed, path);
+                storage.write_leafs()?;
+            }
+        } else {
+            // blocks written after the leafs file (crash in between, or a directory without one)
+            // are added as insert would have
+            let mut leafs: Vec<String> = match fs::read_to_string(storage.path.join(LEAFS_FILE)) {
+                Ok(content) => content.lines().filter(|x| hashes.contains(*x)).map(String::from).collect(),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => vec![],
+                Err(e) => return Err(e),
+            };
+            let mut missing: Vec<&String> = hashes.iter().filter(|x| !referenced.contains(*x) && !leafs.contains(x)).collect();
+            missing.sort();
+            for hash in missing {
+                let prev_hash = storage.get(hash).map(|x| x.prev_hash).unwrap_or_default();
+                add_leaf(&mut leafs, hash.clone(), &prev_hash);
+            }
+            storage.leafs = leafs;
+            storage.write_leafs()?;
+        }
+        Ok(storage)
+    }
+
+    fn write_leafs(&self) -> io::Result<()> {
+        let path = self.path.join(LEAFS_FILE);
+        let tmp_path = path.with_extension("tmp");
+        let mut file = fs::File::create(&tmp_path)?;
+        file.write_all(self.leafs.join("\n").as_bytes())?;
+        file.sync_all()?;
+        fs::rename(tmp_path, &path)?;
+        sync_parent(&path)
+    }
+
+    fn block_path(&self, hash: &str) -> Option<PathBuf> {
+        // hashes come straight from requests, never let them escape the directory
+        if hash.is_empty() || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+            return None;
+        }
+        Some(self.path.join(hash))
+    }
+
+    fn write_block(&self, hash: &str, block: &DataCapsuleBlock) -> io::Result<()> {
+        let path = self.block_path(hash).ok_or(io::Error::from(io::ErrorKind::InvalidInput))?;
+        let tmp_path = path.with_extension("tmp");
+        let mut buf = vec![];
+        block.encode(&mut buf).unwrap();
+
+        let mut file = fs::File::create(&tmp_path)?;
+        file.write_all(&buf)?;
+        file.sync_all()?;
+        fs::rename(tmp_path, &path)?;
+        sync_parent(&path)
+    }
+
+    fn read_block(path: &Path) -> Option<DataCapsuleBlock> {
+        DataCapsuleBlock::decode(fs::read(path).ok()?.as_slice()).ok()
+    }
+}
+
+impl Storage for DirectoryStorage {
+    fn get(&self, hash: &str) -> Option<DataCapsuleBlock> {
+        DirectoryStorage::read_block(&self.block_path(hash)?)
+    }
+
+    fn contains(&self, hash: &str) -> bool {
+        self.block_path(hash).is_some_and(|path| path.is_file())
+    }
+
+    fn insert(&mut self, hash: String, block: DataCapsuleBlock) -> io::Result<()> {
+        self.write_block(&hash, &block)?;
+        self.blocks += 1;
+        self.bytes += block.encoded_len() as u64;
+        add_leaf(&mut self.leafs, hash, &block.prev_hash);
+        self.write_leafs()
+    }
+
+    fn leafs(&self) -> Vec<String> {
+        self.leafs.clone()
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (String, DataCapsuleBlock)> + '_> {
+        let entries = fs::read_dir(&self.path).into_iter().flatten().flatten();
+        Box::new(entries.filter_map(|entry| {
+            let path = entry.path();
+            if path.extension().is_some() {
+                return None; // leftover temporary file
+            }
+            let hash = path.file_name()?.to_str()?.to_string();
+            Some((hash, DirectoryStorage::read_block(&path)?))
+        }))
+    }
+
+    // blocks are already durable, only make sure the renames of their files are too
+    fn snapshot(&mut self) -> io::Result<()> {
+        fs::File::open(&self.path)?.sync_all()
+    }
+
+    fn usage(&self) -> (u64, u64) {
+        (self.blocks, self.bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::sync::broadcast;
+    use tonic::transport::ClientTlsConfig;
+
+    use crate::proto::block::DataCapsuleFileSystemBlock;
+    use crate::test_util::{block, temp_path, verifying_key};
+    use super::*;
+
+    #[test]
+    fn leafs_follow_branches() {
+        let mut leafs = vec!["root".to_string()];
+        add_leaf(&mut leafs, "a".into(), "root");
+        assert_eq!(leafs, vec!["a"]);
+        add_leaf(&mut leafs, "b".into(), "root"); // concurrent with a
+        add_leaf(&mut leafs, "c".into(), "a");
+        assert_eq!(leafs, vec!["b", "c"]);
+    }
+
+    #[test]
+    fn directory_storage_keeps_leafs() {
+        let path = temp_path("leafs");
+        let root = block("", 0);
+        let first = block(&root.hash(), 1);
+        let second = block(&root.hash(), 2);
+        let mut storage = DirectoryStorage::open(path.to_str().unwrap(), None).unwrap();
+        for block in [&root, &first, &second] {
+            storage.insert(block.hash(), block.clone()).unwrap();
+        }
+        assert_eq!(storage.leafs(), vec![first.hash(), second.hash()]);
+
+        let storage = DirectoryStorage::open(path.to_str().unwrap(), None).unwrap();
+        assert_eq!(storage.leafs(), vec![first.hash(), second.hash()]);
+        assert_eq!(storage.usage().0, 3);
+        assert_eq!(storage.get(&first.hash()), Some(first));
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn directory_storage_recovers_leafs() {
+        let path = temp_path("recover");
+        let root = block("", 0);
+        let first = block(&root.hash(), 1);
+        let mut storage = DirectoryStorage::open(path.to_str().unwrap(), None).unwrap();
+        storage.insert(root.hash(), root.clone()).unwrap();
+        storage.insert(first.hash(), first.clone()).unwrap();
+
+        // crash between writing a block and the leafs file
+        let second = block(&first.hash(), 2);
+        storage.write_block(&second.hash(), &second).unwrap();
+        let storage = DirectoryStorage::open(path.to_str().unwrap(), None).unwrap();
+        assert_eq!(storage.leafs(), vec![second.hash()]);
+
+        fs::remove_file(path.join(LEAFS_FILE)).unwrap();
+        let storage = DirectoryStorage::open(path.to_str().unwrap(), None).unwrap();
+        assert_eq!(storage.leafs(), vec![second.hash()]);
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn memory_storage_snapshot() {
+        let data_file = temp_path("data");
+        let log_file = temp_path("log");
+        let root = block("", 0);
+        let mut data = DataCapsuleServerData::default();
+        apply_block(&mut data, root.hash(), root.clone());
+        fs::write(&data_file, data.encode_to_vec()).unwrap();
+
+        let first = block(&root.hash(), 1);
+        let mut storage = MemoryStorage::open(data_file.to_str().unwrap(), log_file.to_str()).unwrap();
+        storage.insert(first.hash(), first.clone()).unwrap();
+        let mut storage = MemoryStorage::open(data_file.to_str().unwrap(), log_file.to_str()).unwrap();
+        assert_eq!(storage.leafs(), vec![first.hash()]); // replayed from the log
+
+        storage.snapshot().unwrap();
+        assert_eq!(fs::metadata(&log_file).unwrap().len(), 0);
+        let storage = MemoryStorage::open(data_file.to_str().unwrap(), None).unwrap();
+        assert_eq!(storage.leafs(), vec![first.hash()]);
+        assert_eq!(storage.usage().0, 2);
+        fs::remove_file(&data_file).unwrap();
+        fs::remove_file(&log_file).unwrap();
+    }
+
+    // an empty capsule kept in memory only
+    fn capsule(replicator: Option<Replicator>) -> MyDataCapsule {
+        let storage = MemoryStorage { data: DataCapsuleServerData::default(), data_file: String::new(), log: None, bytes: 0 };
+        MyDataCapsule {
+            storage: Arc::new(Mutex::new(Box::new(storage))),
+            updates: broadcast::channel(16).0,
+            replicator,
+            quota: Quota::default(),
+            verifying_key: verifying_key(),
+            enable_crypto: false,
+            admin_token: None,
+            shutdown: watch::channel(false).1,
+        }
+    }
+
+    // a block as clients put it, with a file system block
+    fn fs_block(prev_hash: &str, timestamp: i64) -> DataCapsuleBlock {
+        let fs = DataCapsuleFileSystemBlock { prev_hash: prev_hash.into(), ..Default::default() };
+        DataCapsuleBlock { fs: Some(fs), ..block(prev_hash, timestamp) }
+    }
+
+    async fn put(capsule: &MyDataCapsule, block: &DataCapsuleBlock) -> Result<PutResponse, Status> {
+        Ok(capsule.put(Request::new(PutRequest { block: Some(block.clone()) })).await?.into_inner())
+    }
+
+    #[tokio::test]
+    async fn put_does_not_wait_for_peers() {
+        let peer = Replicator::new(vec!["https://127.0.0.1:1".into()], ClientTlsConfig::new()); // nothing listens there
+        let capsule = capsule(Some(peer));
+        let root = fs_block("", 0);
+        let reply = tokio::time::timeout(Duration::from_secs(1), put(&capsule, &root)).await.unwrap().unwrap();
+        assert!(reply.success);
+        assert_eq!(reply.hash, root.hash());
+        assert_eq!(capsule.storage.lock().await.leafs(), vec![root.hash()]);
+    }
+
+    #[tokio::test]
+    async fn put_checks_the_prev_hash() {
+        let capsule = capsule(None);
+        let root = fs_block("", 0);
+        assert!(put(&capsule, &root).await.unwrap().success);
+
+        let unknown = put(&capsule, &fs_block("ab", 1)).await.unwrap_err();
+        assert_eq!(unknown.code(), tonic::Code::FailedPrecondition);
+
+        let mut differing = fs_block(&root.hash(), 1);
+        differing.fs.as_mut().unwrap().prev_hash = "ab".into();
+        assert_eq!(put(&capsule, &differing).await.unwrap_err().code(), tonic::Code::InvalidArgument);
+
+        let missing = block(&root.hash(), 1);
+        assert_eq!(put(&capsule, &missing).await.unwrap_err().code(), tonic::Code::InvalidArgument);
+
+        let child = fs_block(&root.hash(), 1);
+        assert_eq!(put(&capsule, &child).await.unwrap().hash, child.hash());
+        assert_eq!(capsule.storage.lock().await.usage().0, 2);
+    }
+
+    #[tokio::test]
+    async fn genesis_only_into_an_empty_capsule() {
+        let capsule = capsule(None);
+        assert!(put(&capsule, &fs_block("", 0)).await.unwrap().success);
+        let second = put(&capsule, &fs_block("", 1)).await.unwrap_err();
+        assert_eq!(second.code(), tonic::Code::FailedPrecondition);
+        assert_eq!(capsule.storage.lock().await.usage().0, 1);
+    }
+}
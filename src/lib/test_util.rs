@@ -0,0 +1,27 @@
+use std::fs;
+use std::path::PathBuf;
+use std::process;
+
+use rsa::pkcs1v15::VerifyingKey;
+use rsa::pkcs8::DecodePublicKey;
+use rsa::sha2::Sha256;
+
+use crate::proto::block::DataCapsuleBlock;
+
+// a path in the temporary directory that nothing exists at, name has to be unique among the tests
+pub fn temp_path(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("cfs-test-{}-{}", name, process::id()));
+    let _ = fs::remove_dir_all(&path);
+    let _ = fs::remove_file(&path);
+    path
+}
+
+// a block without content, the timestamp tells apart blocks with the same prev_hash
+pub fn block(prev_hash: &str, timestamp: i64) -> DataCapsuleBlock {
+    DataCapsuleBlock { prev_hash: prev_hash.into(), timestamp, ..Default::default() }
+}
+
+// for clients and servers that do not check signatures
+pub fn verifying_key() -> VerifyingKey<Sha256> {
+    VerifyingKey::<Sha256>::from_public_key_pem(include_str!("testdata/public_key.pem")).unwrap()
+}
@@ -0,0 +1,164 @@
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{Read, Write};
+
+use log::warn;
+use prost::bytes::Buf;
+use prost::Message;
+
+use crate::crypto::SignableBlock;
+use crate::proto::block::DataCapsuleBlock;
+use crate::proto::data_capsule::DataCapsuleServerData;
+use crate::server::apply_block;
+
+/* Append-only log of accepted blocks, each stored as a length-delimited DataCapsuleBlock.
+ */
+#[derive(Debug)]
+pub struct WriteAheadLog {
+    file: File,
+}
+
+impl WriteAheadLog {
+    pub fn open(path: &str) -> io::Result<WriteAheadLog> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        Ok(WriteAheadLog { file })
+    }
+
+    // the block is durable once this returns
+    pub fn append(&mut self, block: &DataCapsuleBlock) -> io::Result<()> {
+        let mut buf = vec![];
+        block.encode_length_delimited(&mut buf).unwrap();
+        self.file.write_all(&buf)?;
+        self.file.sync_data()
+    }
+
+    // called once the logged blocks are part of a snapshot
+    pub fn truncate(&mut self) -> io::Result<()> {
+        self.file.set_len(0)?;
+        self.file.sync_data()
+    }
+}
+
+/* Replays the log at `path` on top of `data` and returns the log opened for appending.
+ * A torn record at the end of the log (crash during append) is discarded.
+ */
+pub fn recover(data: &mut DataCapsuleServerData, path: &str) -> io::Result<WriteAheadLog> {
+    let mut content = vec![];
+    match File::open(path) {
+        Ok(mut file) => { file.read_to_end(&mut content)?; }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+
+    let mut buf = content.as_slice();
+    let mut valid_len = 0;
+    let mut replayed = 0;
+    while buf.has_remaining() {
+        match DataCapsuleBlock::decode_length_delimited(&mut buf) {
+            Ok(block) => {
+                let hash = block.hash();
+                if !data.content.contains_key(&hash) {
+                    apply_block(data, hash, block);
+                    replayed += 1;
+                }
+                valid_len = content.len() - buf.remaining();
+            }
+            Err(e) => {
+                warn!("Discarding torn record at offset {} of {}: {}", valid_len, path, e);
+                break;
+            }
+        }
+    }
+    println!("Replayed {} blocks from {}", replayed, path);
+
+    if valid_len != content.len() {
+        OpenOptions::new().write(true).open(path)?.set_len(valid_len as u64)?;
+    }
+    WriteAheadLog::open(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crate::test_util::{block, temp_path};
+    use super::*;
+
+    fn log_path(name: &str) -> String {
+        temp_path(name).to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn replay() {
+        let path = log_path("replay");
+        let first = block("root", 1);
+        let second = block(&first.hash(), 2);
+        let mut log = recover(&mut DataCapsuleServerData::default(), &path).unwrap();
+        log.append(&first).unwrap();
+        log.append(&second).unwrap();
+
+        let mut data = DataCapsuleServerData::default();
+        data.leafs.push("root".into());
+        recover(&mut data, &path).unwrap();
+        assert_eq!(data.content.len(), 2);
+        assert_eq!(data.content[&first.hash()], first);
+        assert_eq!(data.leafs, vec![second.hash()]);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn replay_skips_known_blocks() {
+        let path = log_path("known");
+        let first = block("root", 1);
+        recover(&mut DataCapsuleServerData::default(), &path).unwrap().append(&first).unwrap();
+
+        let mut data = DataCapsuleServerData::default();
+        apply_block(&mut data, first.hash(), first.clone());
+        recover(&mut data, &path).unwrap();
+        assert_eq!(data.content.len(), 1);
+        assert_eq!(data.leafs, vec![first.hash()]);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn torn_record() {
+        let path = log_path("torn");
+        let first = block("root", 1);
+        let second = block(&first.hash(), 2);
+        recover(&mut DataCapsuleServerData::default(), &path).unwrap().append(&first).unwrap();
+        let valid_len = fs::metadata(&path).unwrap().len();
+        let mut torn = vec![];
+        second.encode_length_delimited(&mut torn).unwrap();
+        OpenOptions::new().append(true).open(&path).unwrap().write_all(&torn[..torn.len() - 1]).unwrap();
+
+        let mut data = DataCapsuleServerData::default();
+        let mut log = recover(&mut data, &path).unwrap();
+        assert_eq!(data.content.len(), 1);
+        assert_eq!(fs::metadata(&path).unwrap().len(), valid_len);
+
+        // the log is usable again after the torn record is dropped
+        log.append(&second).unwrap();
+        let mut data = DataCapsuleServerData::default();
+        recover(&mut data, &path).unwrap();
+        assert_eq!(data.content.len(), 2);
+        assert_eq!(data.leafs, vec![second.hash()]);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn truncate() {
+        let path = log_path("truncate");
+        let mut log = recover(&mut DataCapsuleServerData::default(), &path).unwrap();
+        log.append(&block("root", 1)).unwrap();
+        log.truncate().unwrap();
+        log.append(&block("root", 2)).unwrap();
+
+        let mut data = DataCapsuleServerData::default();
+        recover(&mut data, &path).unwrap();
+        assert_eq!(data.content.values().map(|x| x.timestamp).collect::<Vec<_>>(), vec![2]);
+        fs::remove_file(&path).unwrap();
+    }
+}